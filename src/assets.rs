@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap};
 
 use macroquad::{
-    audio::{load_sound_from_bytes, Sound},
+    audio::{load_sound_from_bytes, play_sound, PlaySoundParams, Sound},
+    rand::gen_range,
     texture::Texture2D,
 };
+use serde::Deserialize;
 
-use crate::{level::LevelConfig, scene::Scene};
+use crate::{
+    level::{validate, LevelConfig, LevelError},
+    scene::Scene,
+};
 
 const IMAGES: [(&str, &[u8]); 13] = [
     ("player", include_bytes!("../assets/player.png")),
@@ -35,48 +40,263 @@ const IMAGES: [(&str, &[u8]); 13] = [
     ),
 ];
 
-const LEVELS: [&str; 4] = [
-    include_str!("../assets/level_1.yaml"),
-    include_str!("../assets/level_2.yaml"),
-    include_str!("../assets/level_3.yaml"),
-    include_str!("../assets/level_4.yaml"),
+const LEVELS: [(&str, &str); 4] = [
+    ("level_1", include_str!("../assets/level_1.yaml")),
+    ("level_2", include_str!("../assets/level_2.yaml")),
+    ("level_3", include_str!("../assets/level_3.yaml")),
+    ("level_4", include_str!("../assets/level_4.yaml")),
 ];
 
-pub const SCENES: [&str; 4] = [
-    include_str!("../assets/scene_1.yaml"),
-    include_str!("../assets/scene_2.yaml"),
-    include_str!("../assets/scene_3.yaml"),
-    include_str!("../assets/scene_4.yaml"),
+const SCENES: [(&str, &str); 4] = [
+    ("scene_1", include_str!("../assets/scene_1.yaml")),
+    ("scene_2", include_str!("../assets/scene_2.yaml")),
+    ("scene_3", include_str!("../assets/scene_3.yaml")),
+    ("scene_4", include_str!("../assets/scene_4.yaml")),
 ];
 
-const SOUNDS: [(&str, &[u8]); 9] = [
-    ("stealth", include_bytes!("../assets/Stealth.ogg")),
+const CAMPAIGNS: [&str; 1] = [include_str!("../assets/campaign.yaml")];
+
+/// Sound effects can have several variants to pick from at random
+const SOUNDS: [(&str, &[&[u8]]); 11] = [
+    ("stealth", &[include_bytes!("../assets/Stealth.ogg")]),
     (
         "thief_at_the_kitchen",
-        include_bytes!("../assets/Thief_at_the_kitchen.ogg"),
+        &[include_bytes!("../assets/Thief_at_the_kitchen.ogg")],
+    ),
+    ("village", &[include_bytes!("../assets/village.ogg")]),
+    (
+        "sword",
+        &[
+            include_bytes!("../assets/sword.wav"),
+            include_bytes!("../assets/sword_2.wav"),
+            include_bytes!("../assets/sword_3.wav"),
+        ],
+    ),
+    (
+        "door_unlock",
+        &[include_bytes!("../assets/door_unlock.wav")],
+    ),
+    (
+        "door_locked",
+        &[include_bytes!("../assets/door_locked.wav")],
+    ),
+    (
+        "splat",
+        &[
+            include_bytes!("../assets/splat.wav"),
+            include_bytes!("../assets/splat_2.wav"),
+            include_bytes!("../assets/splat_3.wav"),
+        ],
+    ),
+    ("throw", &[include_bytes!("../assets/throw.wav")]),
+    ("item", &[include_bytes!("../assets/item.ogg")]),
+    ("glass", &[include_bytes!("../assets/glass.wav")]),
+    (
+        "step",
+        &[
+            include_bytes!("../assets/step.wav"),
+            include_bytes!("../assets/step_2.wav"),
+        ],
     ),
-    ("village", include_bytes!("../assets/village.ogg")),
-    ("sword", include_bytes!("../assets/sword.wav")),
-    ("door_unlock", include_bytes!("../assets/door_unlock.wav")),
-    ("door_locked", include_bytes!("../assets/door_locked.wav")),
-    ("splat", include_bytes!("../assets/splat.wav")),
-    ("throw", include_bytes!("../assets/throw.wav")),
-    ("item", include_bytes!("../assets/item.ogg")),
 ];
 
 const END: &str = include_str!("../assets/end.txt");
 
+#[derive(Deserialize)]
+struct CampaignManifest {
+    title: String,
+    author: String,
+    chapters: Vec<ChapterManifest>,
+}
+
+#[derive(Deserialize)]
+struct ChapterManifest {
+    scene: String,
+    level: String,
+}
+
+/// Scene followed by the level it introduces
+pub struct Chapter {
+    pub scene: Scene,
+    pub level: LevelConfig,
+}
+
+/// Campaign picked in the menu, the first one if there is no such campaign
+pub fn active_campaign(campaigns: &[Campaign], index: usize) -> &Campaign {
+    campaigns.get(index).unwrap_or(&campaigns[0])
+}
+
+pub struct Campaign {
+    pub title: String,
+    pub author: String,
+    pub chapters: Vec<Chapter>,
+}
+
+impl Campaign {
+    fn load(
+        manifest: CampaignManifest,
+        scenes: &HashMap<&str, Scene>,
+        levels: &HashMap<&str, LevelConfig>,
+    ) -> Result<Self, AssetError> {
+        let chapters = manifest
+            .chapters
+            .into_iter()
+            .map(|chapter| {
+                Ok(Chapter {
+                    scene: scenes
+                        .get(chapter.scene.as_str())
+                        .ok_or_else(|| AssetError::MissingScene {
+                            campaign: manifest.title.clone(),
+                            key: chapter.scene.clone(),
+                        })?
+                        .clone(),
+                    level: levels
+                        .get(chapter.level.as_str())
+                        .ok_or_else(|| AssetError::MissingLevel {
+                            campaign: manifest.title.clone(),
+                            key: chapter.level.clone(),
+                        })?
+                        .clone(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            title: manifest.title,
+            author: manifest.author,
+            chapters,
+        })
+    }
+}
+
+/// Campaigns of the manifests with the bundled levels and scenes, checked to be playable
+fn load_campaigns(manifests: &[&str]) -> Result<Vec<Campaign>, AssetError> {
+    let levels = LEVELS
+        .into_iter()
+        .enumerate()
+        .map(|(index, (key, level))| {
+            let level = serde_yaml::from_str(level)
+                .map_err(|source| AssetError::Level { index, source })?;
+            validate(&level).map_err(|source| AssetError::InvalidLevel { index, source })?;
+            Ok((key, level))
+        })
+        .collect::<Result<_, _>>()?;
+    let scenes = SCENES
+        .into_iter()
+        .enumerate()
+        .map(|(index, (key, scene))| {
+            serde_yaml::from_str(scene)
+                .map(|scene| (key, scene))
+                .map_err(|source| AssetError::Scene { index, source })
+        })
+        .collect::<Result<HashMap<_, Scene>, _>>()?;
+    for (key, scene) in &scenes {
+        if let Some(image) = scene
+            .images()
+            .find(|image| !IMAGES.iter().any(|(name, _)| name == image))
+        {
+            return Err(AssetError::MissingImage {
+                scene: (*key).to_owned(),
+                key: image.to_owned(),
+            });
+        }
+        if let Some((card, target)) = scene.bad_target() {
+            return Err(AssetError::MissingCard {
+                scene: (*key).to_owned(),
+                card,
+                target,
+            });
+        }
+    }
+    manifests
+        .iter()
+        .enumerate()
+        .map(|(index, campaign)| {
+            let manifest = serde_yaml::from_str(campaign)
+                .map_err(|source| AssetError::Campaign { index, source })?;
+            Campaign::load(manifest, &scenes, &levels)
+        })
+        .collect()
+}
+
+/// Asset that failed to load, with the file it comes from
+#[derive(Debug)]
+pub enum AssetError {
+    Level {
+        index: usize,
+        source: serde_yaml::Error,
+    },
+    InvalidLevel {
+        index: usize,
+        source: LevelError,
+    },
+    Scene {
+        index: usize,
+        source: serde_yaml::Error,
+    },
+    Campaign {
+        index: usize,
+        source: serde_yaml::Error,
+    },
+    Sound {
+        name: String,
+    },
+    MissingScene {
+        campaign: String,
+        key: String,
+    },
+    MissingLevel {
+        campaign: String,
+        key: String,
+    },
+    MissingImage {
+        scene: String,
+        key: String,
+    },
+    MissingCard {
+        scene: String,
+        card: usize,
+        target: usize,
+    },
+}
+
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Level { index, source } => {
+                write!(f, "level {} is broken: {source}", LEVELS[*index].0)
+            }
+            Self::InvalidLevel { index, source } => {
+                write!(f, "level {} is invalid: {source}", LEVELS[*index].0)
+            }
+            Self::Scene { index, source } => {
+                write!(f, "scene {} is broken: {source}", SCENES[*index].0)
+            }
+            Self::Campaign { index, source } => write!(f, "campaign {index} is broken: {source}"),
+            Self::Sound { name } => write!(f, "sound {name} can't be loaded"),
+            Self::MissingScene { campaign, key } => write!(f, "{campaign}: no scene {key}"),
+            Self::MissingLevel { campaign, key } => write!(f, "{campaign}: no level {key}"),
+            Self::MissingImage { scene, key } => write!(f, "scene {scene}: no image {key}"),
+            Self::MissingCard {
+                scene,
+                card,
+                target,
+            } => write!(f, "scene {scene}: card {card} leads to no card {target}"),
+        }
+    }
+}
+
 pub struct Assets {
     pub images: HashMap<String, Texture2D>,
-    pub levels: Vec<LevelConfig>,
-    pub scenes: Vec<Scene>,
-    pub sounds: HashMap<String, Sound>,
+    pub campaigns: Vec<Campaign>,
+    pub sounds: HashMap<String, Vec<Sound>>,
     pub end: Vec<Vec<String>>,
+    /// Variant of each sound effect played last, so it isn't played twice in a row
+    last_variants: RefCell<HashMap<String, usize>>,
 }
 
 impl Assets {
-    pub async fn load() -> Self {
-        let images = IMAGES
+    pub async fn load() -> Result<Self, AssetError> {
+        let images: HashMap<_, _> = IMAGES
             .into_iter()
             .map(|(key, val)| {
                 (
@@ -89,17 +309,20 @@ impl Assets {
             })
             .collect();
         let mut sounds = HashMap::new();
-        for (key, val) in SOUNDS {
-            sounds.insert(key.to_owned(), load_sound_from_bytes(val).await.unwrap());
+        for (key, variants) in SOUNDS {
+            let mut loaded = Vec::new();
+            for val in variants {
+                loaded.push(
+                    load_sound_from_bytes(val)
+                        .await
+                        .map_err(|_| AssetError::Sound {
+                            name: key.to_owned(),
+                        })?,
+                );
+            }
+            sounds.insert(key.to_owned(), loaded);
         }
-        let levels = LEVELS
-            .into_iter()
-            .map(|level| serde_yaml::from_str(level).unwrap())
-            .collect();
-        let scenes = SCENES
-            .into_iter()
-            .map(|scene| serde_yaml::from_str(scene).unwrap())
-            .collect();
+        let campaigns = load_campaigns(&CAMPAIGNS)?;
         let mut end = vec![vec![]];
         for line in END.lines() {
             if line == "..." {
@@ -109,12 +332,95 @@ impl Assets {
             }
         }
 
-        Self {
+        Ok(Self {
             images,
-            levels,
-            scenes,
+            campaigns,
             sounds,
             end,
+            last_variants: RefCell::default(),
+        })
+    }
+
+    /// First variant of the sound, used for music and single clips
+    pub fn sound(&self, name: &str) -> Sound {
+        self.sounds[name][0]
+    }
+
+    /// Plays a random variant of the sound effect so repeats sound less alike
+    pub fn play_sfx_variant(&self, name: &str, volume: f32) {
+        let variants = &self.sounds[name];
+        let mut last_variants = self.last_variants.borrow_mut();
+        let last = last_variants.get(name).copied();
+        let variant = pick_variant(variants.len(), last);
+        last_variants.insert(name.to_owned(), variant);
+        play_sound(
+            variants[variant],
+            PlaySoundParams {
+                looped: false,
+                volume,
+            },
+        );
+    }
+}
+
+/// Random variant out of `len`, other than the last one when there is a choice
+fn pick_variant(len: usize, last: Option<usize>) -> usize {
+    match last {
+        Some(last) if len > 1 => {
+            let variant = gen_range(0, len - 1);
+            if variant >= last {
+                variant + 1
+            } else {
+                variant
+            }
+        }
+        _ => gen_range(0, len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switching_campaigns_changes_the_level() {
+        let reversed = "
+title: Back to front
+author: Someone
+chapters:
+- scene: scene_4
+  level: level_4
+- scene: scene_1
+  level: level_1
+";
+        let campaigns = load_campaigns(&[CAMPAIGNS[0], reversed]).unwrap();
+        assert_eq!(active_campaign(&campaigns, 1).title, "Back to front");
+        let rooms = |index| {
+            active_campaign(&campaigns, index).chapters[0]
+                .level
+                .rooms
+                .len()
+        };
+        let level_1 = serde_yaml::from_str::<LevelConfig>(LEVELS[0].1).unwrap();
+        let level_4 = serde_yaml::from_str::<LevelConfig>(LEVELS[3].1).unwrap();
+        assert_ne!(level_1.rooms.len(), level_4.rooms.len());
+        assert_eq!(rooms(0), level_1.rooms.len());
+        assert_eq!(rooms(1), level_4.rooms.len());
+        // A campaign that is gone falls back to the first one
+        assert_eq!(rooms(2), level_1.rooms.len());
+    }
+
+    #[test]
+    fn variants_dont_repeat_and_all_get_played() {
+        let mut played = [0; 3];
+        let mut last = None;
+        for _ in 0..300 {
+            let variant = pick_variant(played.len(), last);
+            assert_ne!(Some(variant), last);
+            played[variant] += 1;
+            last = Some(variant);
         }
+        assert!(played.iter().all(|&count| count > 0), "{played:?}");
+        assert_eq!(pick_variant(1, Some(0)), 0);
     }
 }
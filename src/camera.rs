@@ -0,0 +1,168 @@
+use macroquad::prelude::{clamp, Vec2};
+
+use crate::{graphics::Screen, RATIO_W_H};
+
+/// Zoom used while panning over the room in the intro
+pub const INTRO_ZOOM: f32 = 2.;
+/// Part of the intro spent panning, the rest zooms out to the whole room
+pub const INTRO_PAN_PART: f32 = 0.75;
+pub const MIN_ZOOM: f32 = 0.75;
+pub const MAX_ZOOM: f32 = 2.5;
+/// Zoom change for one key press
+pub const ZOOM_STEP: f32 = 1.25;
+/// How fast the view catches up with the player, higher is faster
+pub const FOLLOW_SPEED: f32 = 8.;
+/// Jumps of the target longer than this (like going through a door) aren't smoothed
+pub const SNAP_DISTANCE: f32 = 0.5;
+
+#[derive(Clone, Copy)]
+pub enum CameraState {
+    /// Shows the room before handing control to the player
+    Intro {
+        left: f32,
+        total: f32,
+    },
+    Fixed,
+}
+
+pub struct Camera {
+    pub state: CameraState,
+    /// Zoom after the intro, above 1 follows the player
+    pub zoom: f32,
+    /// Center of the view after the intro, trails behind the target
+    pub center: Option<Vec2>,
+}
+
+impl Camera {
+    pub fn new(intro: Option<f32>, zoom: f32) -> Self {
+        Self {
+            state: match intro {
+                Some(total) if total > 0. => CameraState::Intro { left: total, total },
+                _ => CameraState::Fixed,
+            },
+            zoom: clamp(zoom, MIN_ZOOM, MAX_ZOOM),
+            center: None,
+        }
+    }
+
+    /// Multiplies zoom by the factor within the allowed bounds
+    pub fn zoom_by(&mut self, factor: f32) {
+        self.zoom = clamp(self.zoom * factor, MIN_ZOOM, MAX_ZOOM);
+    }
+
+    /// Advances the intro, returns `true` while the player has no control.
+    /// The frame that ends the intro is still its own, so the skip key does nothing else
+    pub fn update(&mut self, dt: f32, skip: bool) -> bool {
+        let CameraState::Intro { left, .. } = &mut self.state else {
+            return false;
+        };
+        *left -= dt;
+        if *left <= 0. || skip {
+            self.state = CameraState::Fixed;
+        }
+        true
+    }
+
+    /// Moves the view smoothly towards the player at `focus`
+    pub fn follow(&mut self, focus: Vec2, dt: f32) {
+        let (target, _) = self.target(focus);
+        self.center = Some(match self.center {
+            Some(center) if center.distance(target) < SNAP_DISTANCE => {
+                center.lerp(target, 1. - (-FOLLOW_SPEED * dt).exp())
+            }
+            _ => target,
+        });
+    }
+
+    /// Center of the view and zoom when the player is at `focus`
+    pub fn target(&self, focus: Vec2) -> (Vec2, f32) {
+        let room = Vec2::new(RATIO_W_H, 1.);
+        // Zoomed out view keeps the whole room in the middle
+        let fixed_center = if self.zoom <= 1. {
+            room / 2.
+        } else {
+            let half_view = room / (2. * self.zoom);
+            focus.clamp(half_view, room - half_view)
+        };
+        let CameraState::Intro { left, total } = self.state else {
+            return (fixed_center, self.zoom);
+        };
+        let progress = 1. - left / total;
+        let half_view = room / (2. * INTRO_ZOOM);
+        let pan_start = half_view;
+        let pan_end = room - half_view;
+        if progress < INTRO_PAN_PART {
+            let t = progress / INTRO_PAN_PART;
+            (pan_start.lerp(pan_end, t), INTRO_ZOOM)
+        } else {
+            let t = (progress - INTRO_PAN_PART) / (1. - INTRO_PAN_PART);
+            (
+                pan_end.lerp(fixed_center, t),
+                INTRO_ZOOM + (self.zoom - INTRO_ZOOM) * t,
+            )
+        }
+    }
+
+    /// Screen to draw the world on, so that the target is in the center
+    pub fn view(&self, screen: &Screen, focus: Vec2) -> Screen {
+        let (target, zoom) = self.target(focus);
+        let center = match self.state {
+            CameraState::Fixed => self.center.unwrap_or(target),
+            CameraState::Intro { .. } => target,
+        };
+        let height = screen.height * zoom;
+        Screen {
+            x: screen.x + screen.width / 2. - center.x * height,
+            y: screen.y + screen.height / 2. - center.y * height,
+            width: screen.width * zoom,
+            height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_is_consumed_by_the_intro() {
+        let mut camera = Camera::new(Some(2.), 1.);
+        assert!(camera.update(0.1, false));
+        // The skipping frame still belongs to the intro
+        assert!(camera.update(0.1, true));
+        assert!(!camera.update(0.1, false));
+    }
+
+    #[test]
+    fn control_returns_after_the_intro() {
+        let mut camera = Camera::new(Some(1.), 1.);
+        assert!(camera.update(0.6, false));
+        assert!(camera.update(0.6, false));
+        assert!(!camera.update(0.1, false));
+        assert!(!Camera::new(None, 1.).update(0.1, true));
+    }
+
+    #[test]
+    fn view_keeps_pixels_and_world_inverse() {
+        let screen = Screen {
+            x: 40.,
+            y: 0.,
+            width: 1280.,
+            height: 720.,
+        };
+        let focus = Vec2::new(1.2, 0.3);
+        for zoom in [MIN_ZOOM, 1., 1.6, MAX_ZOOM] {
+            let view = Camera::new(None, zoom).view(&screen, focus);
+            for world in [Vec2::ZERO, focus, Vec2::new(RATIO_W_H, 1.)] {
+                let back = view.screen_to_world(view.to_px(world));
+                assert!(back.distance(world) < 1e-4, "{zoom}: {world} -> {back}");
+            }
+            let px = Vec2::new(700., 300.);
+            assert!(view.to_px(view.screen_to_world(px)).distance(px) < 1e-2);
+        }
+        // Zoomed in, the player stays in the middle of the screen
+        let view = Camera::new(None, 2.).view(&screen, focus);
+        let center = Vec2::new(screen.x + screen.width / 2., screen.height / 2.);
+        assert!(view.to_px(focus).distance(center) < 1e-2);
+    }
+}
@@ -1,18 +1,25 @@
 #![allow(unused)]
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use macroquad::{
-    prelude::{mouse_position, Color, Vec2, BLACK, WHITE},
+    prelude::{clamp, mouse_position, Color, Vec2, BLACK, DARKGRAY, LIGHTGRAY, RED, WHITE, YELLOW},
     shapes::{draw_circle, draw_line, draw_rectangle},
     text::{draw_text, measure_text},
     texture::{draw_texture_ex, DrawTextureParams},
-    window::clear_background,
+    window::{clear_background, screen_height, screen_width},
 };
 
 use crate::{
     assets::Assets,
-    level::{Item, BALL_RADIUS},
+    level::{Direction, Door, Inventory, Item, Room, BALL_RADIUS, INVENTORY_SIZE},
     RATIO_W_H,
 };
 
+/// Distance between neighbouring rooms on the minimap
+pub const MINIMAP_CELL: f32 = 0.05;
+/// Side of an inventory slot in the HUD
+pub const INVENTORY_SLOT: f32 = 0.07;
+
 pub struct Screen {
     pub x: f32,
     pub y: f32,
@@ -20,6 +27,21 @@ pub struct Screen {
     pub height: f32,
 }
 
+impl Screen {
+    /// Pixel position of the world point
+    pub fn to_px(&self, world: Vec2) -> Vec2 {
+        Vec2::new(
+            world.x * self.height + self.x,
+            world.y * self.height + self.y,
+        )
+    }
+
+    /// World point under the pixel, inverse of `to_px`
+    pub fn screen_to_world(&self, px: Vec2) -> Vec2 {
+        Vec2::new((px.x - self.x) / self.height, (px.y - self.y) / self.height)
+    }
+}
+
 /// Gets screen size from window size for the defined ratio
 pub fn get_screen_size(width: f32, height: f32) -> Screen {
     if width / height > RATIO_W_H {
@@ -41,11 +63,37 @@ pub fn get_screen_size(width: f32, height: f32) -> Screen {
     }
 }
 
+/// Screen shrunk by the inset (part of the height) from the top and bottom, keeping the ratio.
+/// HUD is drawn on it so nothing is cut off by the display edges
+pub fn safe_area(screen: &Screen, inset: f32) -> Screen {
+    let scale = clamp(1. - 2. * inset, 0., 1.);
+    Screen {
+        x: screen.x + screen.width * (1. - scale) / 2.,
+        y: screen.y + screen.height * (1. - scale) / 2.,
+        width: screen.width * scale,
+        height: screen.height * scale,
+    }
+}
+
+/// Keeps a coordinate inside `0..=max`, as it may briefly fall outside while the window resizes
+fn fit(value: f32, max: f32) -> f32 {
+    clamp(value, 0., max)
+}
+
+/// Covers everything outside of the screen, so zoomed in world doesn't leak into the bars
+pub fn draw_letterbox(screen: &Screen) {
+    let (width, height) = (screen_width(), screen_height());
+    draw_rectangle(0., 0., screen.x, height, BLACK);
+    draw_rectangle(screen.x + screen.width, 0., width, height, BLACK);
+    draw_rectangle(0., 0., width, screen.y, BLACK);
+    draw_rectangle(0., screen.y + screen.height, width, height, BLACK);
+}
+
 pub fn draw_rect(screen: &Screen, x: f32, y: f32, w: f32, h: f32, color: Color) {
-    debug_assert!((0. ..=RATIO_W_H).contains(&x));
-    debug_assert!((0. ..=1.).contains(&y));
-    debug_assert!((0. ..=RATIO_W_H).contains(&w));
-    debug_assert!((0. ..=1.).contains(&h));
+    let x = fit(x, RATIO_W_H);
+    let y = fit(y, 1.);
+    let w = fit(w, RATIO_W_H);
+    let h = fit(h, 1.);
     draw_rectangle(
         screen.height * x + screen.x,
         screen.height * y + screen.y,
@@ -56,9 +104,9 @@ pub fn draw_rect(screen: &Screen, x: f32, y: f32, w: f32, h: f32, color: Color)
 }
 
 pub fn draw_circ(screen: &Screen, x: f32, y: f32, r: f32, color: Color) {
-    debug_assert!((0. ..=RATIO_W_H).contains(&x));
-    debug_assert!((0. ..=1.).contains(&y));
-    debug_assert!((0. ..=1.).contains(&r));
+    let x = fit(x, RATIO_W_H);
+    let y = fit(y, 1.);
+    let r = fit(r, 1.);
     draw_circle(
         screen.height * x + screen.x,
         screen.height * y + screen.y,
@@ -67,50 +115,100 @@ pub fn draw_circ(screen: &Screen, x: f32, y: f32, r: f32, color: Color) {
     );
 }
 
+/// Byte ranges of the words of the text
+fn word_ranges(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (n, ch) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (start, ch.is_whitespace()) {
+            (None, false) => start = Some(n),
+            (Some(word), true) => {
+                words.push((word, n));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// Breaks a word wider than the width into pieces at the characters that overflow,
+/// each piece keeps at least one character
+fn split_word(
+    text: &str,
+    (start, end): (usize, usize),
+    width: impl Fn(&str) -> f32,
+    max_width: f32,
+) -> Vec<(usize, usize)> {
+    let mut pieces = Vec::new();
+    let mut piece_start = start;
+    for (n, ch) in text[start..end].char_indices() {
+        let n = start + n;
+        if n > piece_start && width(&text[piece_start..n + ch.len_utf8()]) > max_width {
+            pieces.push((piece_start, n));
+            piece_start = n;
+        }
+    }
+    pieces.push((piece_start, end));
+    pieces
+}
+
+/// Splits the text into lines at word boundaries, so that each line fits into the width.
+/// Words that don't fit into a line on their own are broken. Only the whitespace at the
+/// breaks is dropped, the lines joined together give back every word of the text.
+/// Returns the lines and the width of the longest one
 pub fn get_lines<'a>(
     screen: &Screen,
     max_text_width: f32,
     text_size: f32,
     text: &'a str,
 ) -> (Vec<&'a str>, f32) {
-    let mut result = vec![&text[0..0]];
-    let mut whitespaces: Vec<_> = text
-        .char_indices()
-        .filter_map(|(n, ch)| (ch.is_whitespace()).then_some(n))
-        .collect();
-    whitespaces.push(text.len());
-    let mut start = 0;
-    let mut end = 0;
-    let mut max_len = 0.;
-    for whitespace in whitespaces {
-        let dims = measure_text(
-            &text[start..whitespace],
-            None,
-            (text_size * screen.height) as u16,
-            1.0,
-        );
-        if dims.width > max_text_width * screen.height {
-            start = end + 1;
-            result.push(&text[start..whitespace]);
-        } else {
-            end = whitespace;
-            if max_len < dims.width {
-                max_len = dims.width;
+    let font_size = (text_size * screen.height) as u16;
+    let width = |line: &str| measure_text(line, None, font_size, 1.0).width / screen.height;
+    let result = wrap_lines(text, width, max_text_width);
+    let max_len = result.iter().map(|line| width(line)).fold(0., f32::max);
+    (result, max_len)
+}
+
+/// Line breaking of `get_lines` with the width of a text given by `width`
+fn wrap_lines(text: &str, width: impl Fn(&str) -> f32, max_width: f32) -> Vec<&str> {
+    let mut result = Vec::new();
+    // Byte range of the line being filled
+    let mut line: Option<(usize, usize)> = None;
+    let pieces = word_ranges(text)
+        .into_iter()
+        .flat_map(|word| split_word(text, word, &width, max_width));
+    for (start, end) in pieces {
+        line = match line {
+            Some((line_start, _)) if width(&text[line_start..end]) <= max_width => {
+                Some((line_start, end))
             }
-            if let Some(last) = result.last_mut() {
-                *last = &text[start..end];
+            // The word that didn't fit starts the next line
+            Some((line_start, line_end)) => {
+                result.push(&text[line_start..line_end]);
+                Some((start, end))
             }
-        }
+            None => Some((start, end)),
+        };
     }
-    (result, max_len / screen.height)
+    if let Some((start, end)) = line {
+        result.push(&text[start..end]);
+    }
+    if result.is_empty() {
+        result.push(&text[0..0]);
+    }
+    result
 }
 
 pub fn draw_lin(screen: &Screen, x1: f32, y1: f32, x2: f32, y2: f32, width: f32, color: Color) {
-    debug_assert!((0. ..=RATIO_W_H).contains(&x1));
-    debug_assert!((0. ..=1.).contains(&y1));
-    debug_assert!((0. ..=RATIO_W_H).contains(&x2));
-    debug_assert!((0. ..=1.).contains(&y2));
-    debug_assert!((0. ..=RATIO_W_H).contains(&width));
+    let x1 = fit(x1, RATIO_W_H);
+    let y1 = fit(y1, 1.);
+    let x2 = fit(x2, RATIO_W_H);
+    let y2 = fit(y2, 1.);
+    let width = fit(width, RATIO_W_H);
     draw_line(
         x1 * screen.height + screen.x,
         y1 * screen.height + screen.y,
@@ -122,9 +220,9 @@ pub fn draw_lin(screen: &Screen, x1: f32, y1: f32, x2: f32, y2: f32, width: f32,
 }
 
 pub fn draw_txt(screen: &Screen, text: &str, x: f32, y: f32, font: f32, color: Color) {
-    debug_assert!((0. ..=RATIO_W_H).contains(&x));
-    debug_assert!((0. ..=1.).contains(&y));
-    debug_assert!((0. ..=1.).contains(&font));
+    let x = fit(x, RATIO_W_H);
+    let y = fit(y, 1.);
+    let font = fit(font, 1.);
     draw_text(
         text,
         screen.height * x + screen.x,
@@ -135,8 +233,8 @@ pub fn draw_txt(screen: &Screen, text: &str, x: f32, y: f32, font: f32, color: C
 }
 
 pub fn draw_centered_txt(screen: &Screen, text: &str, y: f32, font: f32, color: Color) {
-    debug_assert!((0. ..=1.).contains(&y));
-    debug_assert!((0. ..=1.).contains(&font));
+    let y = fit(y, 1.);
+    let font = fit(font, 1.);
     let text_dims = measure_text(text, None, (screen.height * font) as u16, 1.);
     let x = (RATIO_W_H - text_dims.width / screen.height) / 2.;
     draw_text(
@@ -150,7 +248,7 @@ pub fn draw_centered_txt(screen: &Screen, text: &str, y: f32, font: f32, color:
 
 pub fn draw_cursor(state: &crate::State, assets: &Assets, screen: &Screen) {
     let cursor = match state {
-        crate::State::Battle(_, level) => &level.level.player.item,
+        crate::State::Battle(_, level, _) => level.level.player.inventory.active(),
         _ => &Item::Sword,
     };
 
@@ -169,4 +267,231 @@ pub fn draw_cursor(state: &crate::State, assets: &Assets, screen: &Screen) {
             ..Default::default()
         },
     );
+    if let Item::Vegetable { count, .. } = cursor {
+        draw_text(
+            &count.to_string(),
+            x_m + 3. * BALL_RADIUS * screen.height,
+            y_m + 3. * BALL_RADIUS * screen.height,
+            0.03 * screen.height,
+            if *count == 0 { RED } else { WHITE },
+        );
+    }
+}
+
+/// Strip of the inventory slots in the top left corner, the selected one is outlined
+pub fn draw_inventory(screen: &Screen, assets: &Assets, inventory: &Inventory) {
+    let margin = 0.02;
+    let border = 0.005;
+    for slot in 0..INVENTORY_SIZE {
+        let x = margin + slot as f32 * (INVENTORY_SLOT + margin);
+        if slot == inventory.selected {
+            draw_rect(
+                screen,
+                x - border,
+                margin - border,
+                INVENTORY_SLOT + 2. * border,
+                INVENTORY_SLOT + 2. * border,
+                YELLOW,
+            );
+        }
+        draw_rect(
+            screen,
+            x,
+            margin,
+            INVENTORY_SLOT,
+            INVENTORY_SLOT,
+            Color::from_rgba(0, 0, 0, 160),
+        );
+        let Some(item) = inventory.items.get(slot) else {
+            continue;
+        };
+        let position = screen.to_px(Vec2::new(x + border, margin + border));
+        let size = (INVENTORY_SLOT - 2. * border) * screen.height;
+        draw_texture_ex(
+            assets.images["items"],
+            position.x,
+            position.y,
+            WHITE,
+            DrawTextureParams {
+                dest_size: Some(Vec2::new(size, size)),
+                source: Some(item.rect()),
+                ..Default::default()
+            },
+        );
+        if let Item::Vegetable { count, .. } = item {
+            draw_txt(
+                screen,
+                &count.to_string(),
+                x + border,
+                margin + INVENTORY_SLOT - border,
+                0.03,
+                if *count == 0 { RED } else { WHITE },
+            );
+        }
+    }
+}
+
+/// Grid cells of the rooms reachable from the entrance, laid out by door directions
+fn minimap_layout(doors: &[Door]) -> HashMap<u8, (i32, i32)> {
+    let mut layout = HashMap::new();
+    let Some(entrance) = doors.iter().find(|door| door.entrance) else {
+        return layout;
+    };
+    layout.insert(entrance.from.0, (0, 0));
+    let mut queue = VecDeque::from([entrance.from]);
+    while let Some(room) = queue.pop_front() {
+        let (x, y) = layout[&room.0];
+        for door in doors.iter().filter(|door| !door.entrance) {
+            let Some((direction, to)) = door.door_from(&room) else {
+                continue;
+            };
+            if layout.contains_key(&to.0) {
+                continue;
+            }
+            let cell = match direction {
+                Direction::North => (x, y - 1),
+                Direction::South => (x, y + 1),
+                Direction::East => (x + 1, y),
+                Direction::West => (x - 1, y),
+            };
+            layout.insert(to.0, cell);
+            queue.push_back(to);
+        }
+    }
+    layout
+}
+
+/// Visited rooms and doors out of them in the top right corner: the current room is
+/// highlighted, cleared rooms are dimmed and closed doors are red
+pub fn draw_minimap(
+    screen: &Screen,
+    doors: &[Door],
+    room: Room,
+    visited: &HashSet<u8>,
+    cleared: &HashSet<u8>,
+) {
+    // The whole level is laid out, so the map doesn't move when new rooms are found
+    let layout = minimap_layout(doors);
+    let (Some(min_x), Some(max_x), Some(min_y), Some(max_y)) = (
+        layout.values().map(|cell| cell.0).min(),
+        layout.values().map(|cell| cell.0).max(),
+        layout.values().map(|cell| cell.1).min(),
+        layout.values().map(|cell| cell.1).max(),
+    ) else {
+        return;
+    };
+    let margin = 0.02;
+    let width = (max_x - min_x + 1) as f32 * MINIMAP_CELL;
+    let height = (max_y - min_y + 1) as f32 * MINIMAP_CELL;
+    let left = RATIO_W_H - margin - width;
+    let top = margin;
+    draw_rect(
+        screen,
+        left,
+        top,
+        width,
+        height,
+        Color::from_rgba(0, 0, 0, 128),
+    );
+    let center = |(x, y): (i32, i32)| {
+        Vec2::new(
+            left + ((x - min_x) as f32 + 0.5) * MINIMAP_CELL,
+            top + ((y - min_y) as f32 + 0.5) * MINIMAP_CELL,
+        )
+    };
+    for door in doors.iter().filter(|door| !door.entrance) {
+        let (Some(&from), Some(&to)) = (layout.get(&door.from.0), layout.get(&door.to.0)) else {
+            continue;
+        };
+        let (from, to) = match (visited.contains(&door.from.0), visited.contains(&door.to.0)) {
+            (true, true) => (center(from), center(to)),
+            // Doors to unknown rooms lead halfway
+            (true, false) => (center(from), (center(from) + center(to)) / 2.),
+            (false, true) => ((center(from) + center(to)) / 2., center(to)),
+            (false, false) => continue,
+        };
+        let color = if door.closed { RED } else { LIGHTGRAY };
+        draw_lin(screen, from.x, from.y, to.x, to.y, 0.004, color);
+    }
+    let size = 0.6 * MINIMAP_CELL;
+    for (id, &cell) in layout.iter().filter(|(id, _)| visited.contains(id)) {
+        let color = if *id == room.0 {
+            YELLOW
+        } else if cleared.contains(id) {
+            DARKGRAY
+        } else {
+            LIGHTGRAY
+        };
+        let center = center(cell);
+        draw_rect(
+            screen,
+            center.x - size / 2.,
+            center.y - size / 2.,
+            size,
+            size,
+            color,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every character is one unit wide
+    fn chars(text: &str) -> f32 {
+        text.chars().count() as f32
+    }
+
+    #[test]
+    fn wrapped_lines_keep_every_word() {
+        let text = "The cook keeps the  best knives behind the stove, so\nsneak past him quietly";
+        let lines = wrap_lines(text, chars, 12.);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| chars(line) <= 12.));
+        let words: Vec<_> = text.split_whitespace().collect();
+        let wrapped: Vec<_> = lines
+            .iter()
+            .flat_map(|line| line.split_whitespace())
+            .collect();
+        assert_eq!(wrapped, words);
+    }
+
+    #[test]
+    fn long_word_is_broken_to_the_width() {
+        let word = "a".repeat(40);
+        let text = format!("Take the {word} now");
+        let lines = wrap_lines(&text, chars, 7.);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| chars(line) <= 7.), "{lines:?}");
+        assert_eq!(lines.concat().replace(' ', ""), text.replace(' ', ""));
+    }
+
+    #[test]
+    fn safe_area_shrinks_every_side() {
+        let screen = Screen {
+            x: 100.,
+            y: 20.,
+            width: 1600.,
+            height: 900.,
+        };
+        let area = safe_area(&screen, 0.05);
+        // Each side moves in by the inset, relative to its own axis
+        assert!((area.x - 180.).abs() < 1e-3);
+        assert!((area.y - 65.).abs() < 1e-3);
+        assert!((screen.x + screen.width - area.x - area.width - 80.).abs() < 1e-3);
+        assert!((screen.y + screen.height - area.y - area.height - 45.).abs() < 1e-3);
+        // No inset keeps the screen, a negative one doesn't grow it
+        for inset in [0., -0.2] {
+            let area = safe_area(&screen, inset);
+            assert_eq!(
+                (area.x, area.y, area.width, area.height),
+                (screen.x, screen.y, screen.width, screen.height)
+            );
+        }
+        // Half the screen or more leaves nothing, centered
+        let area = safe_area(&screen, 0.7);
+        assert_eq!((area.width, area.height), (0., 0.));
+        assert_eq!((area.x, area.y), (900., 470.));
+    }
 }
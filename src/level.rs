@@ -1,29 +1,93 @@
 use std::{
-    cmp::Ordering,
-    collections::HashMap,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
     f32::consts::{FRAC_PI_2, FRAC_PI_3},
-    hash::Hash,
+    ops::RangeInclusive,
 };
 
-use macroquad::{audio::play_sound_once, prelude::*, rand::gen_range};
+use macroquad::{prelude::*, rand::gen_range};
 use serde::Deserialize;
 
 use crate::{
     assets::Assets,
-    graphics::{draw_centered_txt, draw_rect, draw_txt, get_lines, Screen},
+    camera::{Camera, CameraState, ZOOM_STEP},
+    graphics::{
+        draw_centered_txt, draw_circ, draw_inventory, draw_letterbox, draw_minimap, draw_rect,
+        draw_txt, get_lines, safe_area, Screen,
+    },
+    settings::{key_name, Difficulty, KeyBindings, Settings},
     RATIO_W_H,
 };
 
 pub const BALL_SPEED: f32 = 1.75;
 pub const PLAYER_RADIUS: f32 = 0.025;
 pub const BALL_RADIUS: f32 = 0.01;
+/// Seconds a thrown item flies before falling down
+pub const BALL_MAX_LIFETIME: f32 = 1.2;
+/// Part of the speed a thrown item keeps after a second
+pub const BALL_DECAY: f32 = 0.6;
 pub const WALL_SIZE: f32 = 0.02;
+/// Furthest a body is pushed out of others in one tick
+pub const MAX_COLLISION_SHIFT: f32 = PLAYER_RADIUS;
+/// Throws a vegetable has when the level doesn't set it
+pub const VEGETABLE_COUNT: u32 = 30;
+/// Items the player can carry at once
+pub const INVENTORY_SIZE: usize = 3;
 
 pub const SPEED_STEPS: i32 = 10;
 pub const PLAYER_MAX_SPEED: f32 = 0.65;
 pub const PLAYER_RELOAD: f32 = 0.5;
 pub const SLASH_LEN: f32 = 0.02;
 pub const HEAL_TIME: f32 = 5.;
+/// Seconds the player with critical health lives
+pub const BLEED_TIME: f32 = 10.;
+pub const LAST_STAND_TIME: f32 = 2.;
+pub const LAST_STAND_SCALE: f32 = 0.3;
+pub const ALERT_TIME: f32 = 0.5;
+pub const ALERT_DECAY_TIME: f32 = 3.;
+pub const DECOY_RANGE: f32 = 0.6;
+/// Angle in degrees that guards see in front of them, closer than the touch
+/// distance they notice the player from any side
+pub const ENEMY_FOV: f32 = 120.;
+/// How far into the next room the player sees from a doorway
+pub const PEEK_DEPTH: f32 = 0.5;
+/// Ticks of the player's path kept for the ghost, a minute
+pub const GHOST_TICKS: usize = 60 * 60;
+/// Size of a pathfinding grid cell
+pub const GRID_CELL: f32 = 2. * PLAYER_RADIUS;
+/// Seconds between route recalculations of a guard
+pub const ROUTE_TIME: f32 = 0.25;
+/// How far throwing guards throw
+pub const ENEMY_THROW_RANGE: f32 = 0.8;
+/// Seconds between footsteps at full speed
+pub const STEP_INTERVAL: f32 = 0.3;
+/// Crouching steps are further apart
+pub const CROUCH_STEP_INTERVAL: f32 = 0.5;
+/// How far guards hear the player running
+pub const STEP_NOISE_RADIUS: f32 = 0.35;
+/// How far guards hear the player sprinting or throwing
+pub const NOISE_RADIUS: f32 = 0.6;
+/// Speed of the sprinting player compared to walking
+pub const SPRINT_SCALE: f32 = 1.6;
+/// Seconds of sprinting on full stamina
+pub const SPRINT_TIME: f32 = 3.;
+/// Seconds to recover full stamina
+pub const STAMINA_RECOVERY_TIME: f32 = 5.;
+/// Stamina needed to sprint again after running out of it
+pub const STAMINA_THRESHOLD: f32 = 0.3;
+/// Seconds a dead guard lies before fading away, when corpses fade
+pub const CORPSE_TIME: f32 = 5.;
+pub const CORPSE_FADE_TIME: f32 = 2.;
+/// Seconds of a simulation tick, the level plays the same at any frame rate
+pub const TICK: f32 = 1. / 60.;
+/// Most ticks in a frame, a long frame doesn't throw the bodies far
+pub const MAX_TICKS: u32 = 5;
+/// Sounds this far from the player are played the quietest
+pub const HEARING_DISTANCE: f32 = RATIO_W_H;
+/// Seconds a caption stays on screen
+pub const CAPTION_TIME: f32 = 5.;
+/// Captions shown at once, older ones are dropped
+pub const CAPTION_COUNT: usize = 4;
 
 #[derive(Clone)]
 pub struct Velocity(pub Vec2);
@@ -60,10 +124,14 @@ impl Position {
 #[derive(Clone)]
 pub struct Sight(pub Vec2);
 
+/// Every hit takes one step down. The player starts `Full`, guards start `Low` when
+/// tough and `Critical` otherwise
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Health {
     Full,
     Low,
+    /// The player bleeds out from here
+    Critical,
     Dead,
 }
 
@@ -71,7 +139,16 @@ impl Health {
     pub fn decrease(&mut self) {
         *self = match self {
             Self::Full => Self::Low,
-            Self::Low | Self::Dead => Self::Dead,
+            Self::Low => Self::Critical,
+            Self::Critical | Self::Dead => Self::Dead,
+        };
+    }
+    /// Heals a tier, the dead stay dead
+    pub fn increase(&mut self) {
+        *self = match self {
+            Self::Full | Self::Low => Self::Full,
+            Self::Critical => Self::Low,
+            Self::Dead => Self::Dead,
         };
     }
 }
@@ -82,10 +159,20 @@ pub struct Phrase {
     pub time: f32,
 }
 
+/// Phrase said recently, kept for the captions
+#[derive(Clone)]
+pub struct Caption {
+    /// Index of the guard, `None` for the player
+    pub speaker: Option<usize>,
+    pub text: String,
+    /// Seconds left on screen
+    pub time: f32,
+}
+
 #[derive(Default, Clone)]
 pub struct Reload(pub f32);
 
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub struct Room(pub u8);
 
 #[derive(Clone)]
@@ -103,28 +190,124 @@ pub struct Player {
     pub body: Body,
     pub reload: Reload,
     pub health: Health,
-    pub item: Item,
+    pub inventory: Inventory,
     pub visible: bool,
     pub heal_time: f32,
+    /// Seconds bled with critical health
+    pub bleed_time: f32,
+    pub hazard_time: f32,
+    /// Time until the next footstep, grows with speed
+    pub step_time: f32,
+    /// Part of the sprint left
+    pub stamina: f32,
+    /// Ran out of stamina and can't sprint until it recovers
+    pub exhausted: bool,
+    pub sprinting: bool,
+}
+
+/// Items the player carries, the selected one is in hand
+#[derive(Clone)]
+pub struct Inventory {
+    pub items: Vec<Item>,
+    pub selected: usize,
+}
+
+impl Inventory {
+    pub fn new(item: Item) -> Self {
+        Self {
+            items: vec![item],
+            selected: 0,
+        }
+    }
+
+    pub fn active(&self) -> &Item {
+        &self.items[self.selected]
+    }
+
+    pub fn active_mut(&mut self) -> &mut Item {
+        &mut self.items[self.selected]
+    }
+
+    pub fn contains(&self, item: &Item) -> bool {
+        self.items.contains(item)
+    }
+
+    /// Selects the next item, or the previous one for a negative step
+    pub fn cycle(&mut self, step: isize) {
+        let len = self.items.len() as isize;
+        self.selected = (self.selected as isize + step).rem_euclid(len) as usize;
+    }
+
+    /// Puts the item into a free slot and selects it. When full the item in hand is
+    /// exchanged and returned instead
+    pub fn take(&mut self, item: Item) -> Option<Item> {
+        if self.items.len() < INVENTORY_SIZE {
+            self.items.push(item);
+            self.selected = self.items.len() - 1;
+            None
+        } else {
+            Some(std::mem::replace(self.active_mut(), item))
+        }
+    }
+
+    /// Takes the item in hand out and selects the previous one. The sword and the last
+    /// item are kept
+    pub fn drop(&mut self) -> Option<Item> {
+        if self.items.len() < 2 || *self.active() == Item::Sword {
+            return None;
+        }
+        let item = self.items.remove(self.selected);
+        self.selected = self.selected.saturating_sub(1);
+        Some(item)
+    }
+}
+
+/// What happens to a guard hit by a vegetable
+#[derive(Clone, Copy, Default, serde::Deserialize, PartialEq)]
+pub enum Effect {
+    #[default]
+    Damage,
+    /// Guard can't move or attack for the given time
+    Stun(f32),
+    /// Lures guards around to where it landed for the given time
+    Decoy(f32),
 }
 
-#[derive(Clone, serde::Deserialize, PartialEq, Eq)]
+#[derive(Clone, serde::Deserialize, PartialEq)]
 pub enum Item {
     Sword,
     Key,
+    /// Heals a tier of health when used, then it's gone
+    Bandage,
     Vegetable {
         name: String,
         idx: usize,
         color: (u8, u8, u8, u8),
+        radius: Option<f32>,
+        speed: Option<f32>,
+        #[serde(default)]
+        effect: Effect,
+        /// Throws left
+        #[serde(default = "vegetable_count")]
+        count: u32,
     },
 }
 
+fn vegetable_count() -> u32 {
+    VEGETABLE_COUNT
+}
+
 #[derive(Clone)]
 pub struct ItemCrate {
     pub item: Item,
     pub position: Position,
     pub form: Form,
     pub room: Room,
+    /// Item left on the floor rather than a crate of the level, it can be picked up by
+    /// walking over it
+    pub dropped: bool,
+    /// The player hasn't stepped off the item since dropping it
+    pub under_player: bool,
 }
 
 impl ItemCrate {
@@ -137,6 +320,17 @@ impl ItemCrate {
                 width: 1.5 * PLAYER_RADIUS,
                 height: 1.5 * PLAYER_RADIUS,
             },
+            dropped: false,
+            under_player: false,
+        }
+    }
+
+    /// Item left on the floor, `under_player` when the player dropped it
+    pub fn dropped(item: Item, position: Position, room: Room, under_player: bool) -> Self {
+        Self {
+            dropped: true,
+            under_player,
+            ..Self::new(item, position, room)
         }
     }
 }
@@ -146,13 +340,32 @@ impl Item {
         match self {
             Self::Sword => Rect::new(80., 20., 100., 120.),
             Self::Key => Rect::new(200., 20., 60., 60.),
+            Self::Bandage => Rect::new(270., 20., 60., 60.),
             Self::Vegetable { idx, .. } => Rect::new(20. + (*idx as f32 * 60.), 150., 50., 50.),
         }
     }
+    pub fn ball_radius(&self) -> f32 {
+        match self {
+            Self::Vegetable {
+                radius: Some(radius),
+                ..
+            } => *radius,
+            _ => BALL_RADIUS,
+        }
+    }
+    pub fn ball_speed(&self) -> f32 {
+        match self {
+            Self::Vegetable {
+                speed: Some(speed), ..
+            } => *speed,
+            _ => BALL_SPEED,
+        }
+    }
     pub fn name(&self) -> String {
         match self {
             Self::Sword => "sword",
             Self::Key => "key",
+            Self::Bandage => "bandage",
             Self::Vegetable { name, .. } => name,
         }
         .to_owned()
@@ -162,6 +375,7 @@ impl Item {
 #[derive(Default, Clone)]
 pub enum EnemyState {
     Fight(Vec2, Form),
+    Suspicious(Vec2),
     LastSeen(Vec2, f32),
     #[default]
     Idle,
@@ -174,20 +388,55 @@ pub struct Enemy {
     pub body: Body,
     pub reload: Reload,
     pub state: EnemyState,
+    pub alertness: f32,
+    /// How far a shout of another guard can be heard
+    pub alert_radius: f32,
     pub post: Post,
     pub health: Health,
+    /// Started with full health, so wounds are shown
+    pub tough: bool,
+    /// Seconds since death
+    pub dead_time: f32,
     pub stain: Option<Color>,
+    pub stun: f32,
+    /// Item dropped when the guard dies
+    pub carries: Option<Item>,
+    /// Item thrown at a player out of reach, melee only if not set
+    pub throws: Option<Item>,
+    /// Part of the player's speed the guard moves with
+    pub speed: f32,
+    /// Points the guard walks between in a loop instead of standing at the post
+    pub patrol: Vec<Vec2>,
+    /// Index of the patrol point the guard walks to, wraps around to the first one
+    pub waypoint: usize,
+    /// Waypoints around the crates to the current target
+    pub route: Vec<Vec2>,
+    pub route_time: f32,
+    pub step_time: f32,
 }
 
 #[derive(Clone)]
 pub struct Ball {
     pub position: Position,
+    pub radius: f32,
     pub velocity: Velocity,
+    /// Seconds since the throw
+    pub lifetime: f32,
     pub room: Room,
     pub item: Item,
+    /// Thrown by a guard, hits only the player
+    pub hostile: bool,
+}
+
+#[derive(Clone)]
+pub struct Decoy {
+    pub item: Item,
+    pub position: Position,
+    pub room: Room,
+    pub time: f32,
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq, Deserialize)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 pub enum Direction {
     North,
     South,
@@ -213,17 +462,27 @@ pub struct Door {
     pub to: Room,
     pub closed: bool,
     pub entrance: bool,
+    /// Window or vent that opens when something is thrown into it
+    pub breakable: bool,
     pub playing: f32,
 }
 
 impl Door {
-    pub fn new(from: Room, to: Room, direction: Direction, closed: bool, entrance: bool) -> Self {
+    pub fn new(
+        from: Room,
+        to: Room,
+        direction: Direction,
+        closed: bool,
+        entrance: bool,
+        breakable: bool,
+    ) -> Self {
         Self {
             direction,
             from,
             to,
             closed,
             entrance,
+            breakable,
             playing: 0.,
         }
     }
@@ -245,7 +504,7 @@ impl PartialEq for Door {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct MoveAction {
     pub move_direction: (i32, i32),
     pub sight: Vec2,
@@ -260,6 +519,147 @@ impl Default for MoveAction {
     }
 }
 
+/// What the player wants to do this frame, read from the keys and the mouse or made up
+#[derive(Clone, Copy, Default)]
+pub struct PlayerInput {
+    /// Movement and where the player looks
+    pub move_action: MoveAction,
+    pub crouch: bool,
+    pub sprint: bool,
+    pub attack: bool,
+    /// Inventory slot picked by its number key
+    pub slot: Option<usize>,
+    /// Inventory slots to step through, negative goes back
+    pub cycle: isize,
+}
+
+impl PlayerInput {
+    pub fn read(screen: &Screen, player: &Player, settings: &Settings) -> Self {
+        let keys = &settings.keys;
+        let slot = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3]
+            .into_iter()
+            .rposition(is_key_pressed);
+        let (_, wheel) = mouse_wheel();
+        let cycle = if wheel > 0. {
+            -1
+        } else if wheel < 0. {
+            1
+        } else {
+            0
+        };
+        let mouse = screen.screen_to_world(mouse_position().into());
+        let mouse = Vec2::new(clamp(mouse.x, 0., RATIO_W_H), clamp(mouse.y, 0., 1.));
+        Self {
+            move_action: MoveAction {
+                move_direction: keys.move_direction(is_key_down),
+                sight: (mouse - player.body.position.0).normalize_or_zero(),
+            },
+            crouch: is_key_pressed(keys.crouch),
+            sprint: is_key_down(keys.sprint),
+            attack: is_mouse_button_down(keys.attack),
+            slot,
+            cycle,
+        }
+    }
+}
+
+/// Everything the player does in a frame, read at once so that the level steps without a window
+#[derive(Clone, Default)]
+pub struct LevelInput {
+    pub player: PlayerInput,
+    /// Swap items with the crate in reach
+    pub interact: bool,
+    pub drop: bool,
+    pub peek: bool,
+    pub skip: bool,
+    pub zoom_in: bool,
+    pub zoom_out: bool,
+    pub restart: bool,
+    pub restart_level: bool,
+    pub minimap: bool,
+    pub captions: bool,
+}
+
+impl LevelInput {
+    /// Adds the input of a frame. Held keys and the aim follow the latest frame, presses
+    /// add up until a tick takes them
+    fn merge(&mut self, frame: Self) {
+        let player = &self.player;
+        *self = Self {
+            player: PlayerInput {
+                crouch: player.crouch || frame.player.crouch,
+                slot: frame.player.slot.or(player.slot),
+                cycle: player.cycle + frame.player.cycle,
+                ..frame.player
+            },
+            interact: self.interact || frame.interact,
+            drop: self.drop || frame.drop,
+            skip: self.skip || frame.skip,
+            zoom_in: self.zoom_in || frame.zoom_in,
+            zoom_out: self.zoom_out || frame.zoom_out,
+            restart: self.restart || frame.restart,
+            restart_level: self.restart_level || frame.restart_level,
+            minimap: self.minimap || frame.minimap,
+            captions: self.captions || frame.captions,
+            ..frame
+        };
+    }
+
+    /// Input left for the following ticks once the presses are taken
+    fn held(&self) -> Self {
+        Self {
+            player: PlayerInput {
+                move_action: self.player.move_action,
+                sprint: self.player.sprint,
+                attack: self.player.attack,
+                ..Default::default()
+            },
+            peek: self.peek,
+            ..Default::default()
+        }
+    }
+
+    pub fn read(screen: &Screen, player: &Player, settings: &Settings) -> Self {
+        let keys = &settings.keys;
+        Self {
+            player: PlayerInput::read(screen, player, settings),
+            interact: is_key_pressed(keys.interact),
+            drop: is_key_pressed(keys.drop),
+            peek: is_key_down(keys.peek),
+            skip: keys.skip.iter().any(|key| is_key_pressed(*key)),
+            zoom_in: is_key_pressed(keys.zoom_in),
+            zoom_out: is_key_pressed(keys.zoom_out),
+            restart: is_key_pressed(keys.restart),
+            restart_level: is_key_pressed(keys.restart_level),
+            minimap: is_key_pressed(keys.minimap),
+            captions: is_key_pressed(keys.captions),
+        }
+    }
+}
+
+/// Sound effect the level made, played after the step
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundEvent {
+    pub name: &'static str,
+    pub volume: f32,
+}
+
+impl SoundEvent {
+    pub const fn new(name: &'static str, volume: f32) -> Self {
+        Self { name, volume }
+    }
+}
+
+/// What a step of the level led to
+#[derive(Default)]
+pub struct Step {
+    /// The player left the level through the entrance
+    pub next: bool,
+    /// The player swapped items with a crate
+    pub swapped: bool,
+    pub sounds: Vec<SoundEvent>,
+}
+
 #[derive(Clone, Copy)]
 pub enum Form {
     Circle { radius: f32 },
@@ -280,11 +680,15 @@ impl Form {
         }
     }
 
+    /// Distance from the center to the border along the direction, the nearest side
+    /// without a direction
     pub fn direction_len(&self, n: Vec2) -> f32 {
         match self {
             Self::Circle { radius } => *radius,
             Self::Rect { width, height } => {
-                let n = n.normalize();
+                let Some(n) = n.try_normalize() else {
+                    return width.min(*height);
+                };
                 let x_ratio = width / n.x.abs();
                 let y_ratio = height / n.y.abs();
                 match x_ratio.total_cmp(&y_ratio) {
@@ -296,6 +700,63 @@ impl Form {
     }
 }
 
+#[derive(Clone, Copy, Deserialize)]
+pub enum HazardKind {
+    /// Hurts the player every `interval` seconds spent inside
+    Damage { interval: f32 },
+    /// Multiplies the speed of every body inside
+    Slow { factor: f32 },
+}
+
+#[derive(Clone)]
+pub struct Hazard {
+    pub rect: Rect,
+    pub kind: HazardKind,
+    pub room: Room,
+}
+
+impl Hazard {
+    pub fn covers(&self, body: &Body) -> bool {
+        self.room == body.room && self.rect.contains(body.position.0)
+    }
+}
+
+/// Speed multiplier of the slowest hazard the body is in
+fn hazard_slow(hazards: &[Hazard], body: &Body) -> f32 {
+    hazards
+        .iter()
+        .filter(|hazard| hazard.covers(body))
+        .fold(1., |slow, hazard| match hazard.kind {
+            HazardKind::Slow { factor } => f32::min(slow, factor),
+            HazardKind::Damage { .. } => slow,
+        })
+}
+
+/// Hurts the player once per interval of the fastest damaging hazard they stand in,
+/// returns whether they were hurt
+fn hazard_damage(hazards: &[Hazard], player: &mut Player, dt: f32) -> bool {
+    let interval = hazards
+        .iter()
+        .filter(|hazard| hazard.covers(&player.body))
+        .filter_map(|hazard| match hazard.kind {
+            HazardKind::Damage { interval } => Some(interval),
+            HazardKind::Slow { .. } => None,
+        })
+        .reduce(f32::min);
+    match interval {
+        Some(interval) if player.health != Health::Dead => {
+            player.hazard_time += dt;
+            if player.hazard_time >= interval {
+                player.hazard_time = 0.;
+                player.health.decrease();
+                return true;
+            }
+        }
+        _ => player.hazard_time = 0.,
+    }
+    false
+}
+
 #[derive(Clone)]
 struct Stain {
     color: Color,
@@ -307,6 +768,20 @@ struct Stain {
 #[derive(Deserialize, Clone)]
 pub struct LevelConfig {
     pub rooms: Vec<RoomConfig>,
+    /// Duration of the camera pan over the room at the start
+    pub intro: Option<f32>,
+    /// Slow the time down once per room when the wounded player is fought by several guards
+    #[serde(default)]
+    pub last_stand: bool,
+    /// Random seed, so guards and crates are placed the same way every time
+    pub seed: Option<u64>,
+    /// Whether the wounded player heals, the setting decides if not set
+    pub heal: Option<bool>,
+    /// Seconds with low health before healing, the setting decides if not set
+    pub heal_time: Option<f32>,
+    /// The player may leave with guards alive while none of them is fighting
+    #[serde(default)]
+    pub leave_alive: bool,
 }
 
 #[derive(Clone, Deserialize)]
@@ -315,7 +790,21 @@ pub struct RoomConfig {
     pub enter: Option<Direction>,
     pub doors: Vec<DoorConfig>,
     pub items: Option<Vec<Item>>,
-    pub enemies: u8,
+    pub enemies: Enemies,
+    pub hazards: Option<Vec<HazardConfig>>,
+    /// How far guards hear each other shouting, the whole room if not set
+    pub alert_radius: Option<f32>,
+    /// Items carried by the guards of the room, one per guard
+    pub carried: Option<Vec<Item>>,
+    /// Patrol points of the guards of the room, one list per guard
+    pub patrols: Option<Vec<Vec<[f32; 2]>>>,
+    /// Items thrown by the guards of the room, one per guard
+    pub throws: Option<Vec<Item>>,
+    /// Part of the player's speed the guards of the room move with, same speed if not set
+    pub enemy_speed: Option<f32>,
+    /// Guards of the room take two hits instead of one
+    #[serde(default)]
+    pub tough_enemies: bool,
 }
 
 impl PartialEq for RoomConfig {
@@ -325,35 +814,268 @@ impl PartialEq for RoomConfig {
 }
 impl Eq for RoomConfig {}
 
-impl Hash for RoomConfig {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-    }
-}
-
 #[derive(Clone, Deserialize)]
 pub struct DoorConfig {
     pub direction: Direction,
     pub to: u8,
     #[serde(default)]
     pub closed: bool,
+    #[serde(default)]
+    pub breakable: bool,
+}
+
+/// Number of guards in the room, either exact or drawn from a range
+#[derive(Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Enemies {
+    Fixed(u8),
+    Range { min: u8, max: u8 },
+}
+
+impl Enemies {
+    /// Fewest guards the room can have on any difficulty
+    pub fn min(self) -> u8 {
+        match self {
+            Self::Fixed(count) => count,
+            Self::Range { min, .. } => min,
+        }
+    }
+
+    pub fn count(self, difficulty: Difficulty) -> u8 {
+        let count = match self {
+            Self::Fixed(count) => count,
+            Self::Range { min, max } => {
+                let max = max.max(min);
+                let random: f32 = gen_range(0., 1.);
+                // Harder difficulties pick from the top of the range more often
+                let random = match difficulty {
+                    Difficulty::Easy => random * random,
+                    Difficulty::Normal => random,
+                    Difficulty::Hard => random.sqrt(),
+                };
+                // Widened, a range of all 256 counts doesn't fit in u8
+                let span = u16::from(max - min) + 1;
+                let count = ((span as f32 * random) as u16).min(span - 1);
+                min + count as u8
+            }
+        };
+        // Rooms without guards stay empty
+        if count > 0 {
+            count.saturating_add(difficulty.params().extra_guards)
+        } else {
+            count
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct HazardConfig {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub kind: HazardKind,
+}
+
+#[derive(Debug)]
+pub enum LevelError {
+    NoEntry,
+    DuplicateRoomId(u8),
+    DoorToMissingRoom {
+        room: u8,
+        to: u8,
+    },
+    /// Two doors or a door and the entrance in the same wall
+    ConflictingDoors {
+        room: u8,
+        direction: Direction,
+    },
+    /// Guards can only throw vegetables
+    NotThrowable(u8),
+    /// Guard range with the minimum above the maximum
+    EnemyRange(u8),
+    /// More carried items than the fewest guards the room can have, some could be lost
+    CarriedItems(u8),
+}
+
+impl std::fmt::Display for LevelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoEntry => write!(f, "level has no entry room"),
+            Self::DuplicateRoomId(id) => write!(f, "several rooms have id {id}"),
+            Self::DoorToMissingRoom { room, to } => {
+                write!(f, "room {room} has a door to missing room {to}")
+            }
+            Self::ConflictingDoors { room, direction } => {
+                write!(f, "room {room} has several doors in the {direction:?} wall")
+            }
+            Self::NotThrowable(room) => write!(f, "guards of room {room} throw a non-vegetable"),
+            Self::EnemyRange(room) => {
+                write!(f, "room {room} has a guard range with min above max")
+            }
+            Self::CarriedItems(room) => {
+                write!(f, "room {room} has more carried items than guards")
+            }
+        }
+    }
+}
+
+/// Checks that the rooms of the level can be connected and entered
+pub fn validate(config: &LevelConfig) -> Result<(), LevelError> {
+    let mut ids = HashSet::new();
+    for room in &config.rooms {
+        if !ids.insert(room.id) {
+            return Err(LevelError::DuplicateRoomId(room.id));
+        }
+    }
+    for room in &config.rooms {
+        if let Some(door) = room.doors.iter().find(|door| !ids.contains(&door.to)) {
+            return Err(LevelError::DoorToMissingRoom {
+                room: room.id,
+                to: door.to,
+            });
+        }
+    }
+    for room in &config.rooms {
+        // Doors are described from either side, the entrance leads nowhere
+        let own = room.doors.iter().map(|door| (door.direction, door.to));
+        let others = config.rooms.iter().flat_map(|other| {
+            other
+                .doors
+                .iter()
+                .filter(|door| door.to == room.id)
+                .map(|door| (door.direction.inverse(), other.id))
+        });
+        let entrance = room.enter.map(|enter| (enter, u8::MAX));
+        let mut walls = BTreeMap::new();
+        for (direction, to) in own.chain(others).chain(entrance) {
+            if walls.insert(direction, to).is_some_and(|other| other != to) {
+                return Err(LevelError::ConflictingDoors {
+                    room: room.id,
+                    direction,
+                });
+            }
+        }
+    }
+    if let Some(room) = config.rooms.iter().find(|room| {
+        room.throws
+            .iter()
+            .flatten()
+            .any(|item| !matches!(item, Item::Vegetable { .. }))
+    }) {
+        return Err(LevelError::NotThrowable(room.id));
+    }
+    if let Some(room) = config
+        .rooms
+        .iter()
+        .find(|room| matches!(room.enemies, Enemies::Range { min, max } if min > max))
+    {
+        return Err(LevelError::EnemyRange(room.id));
+    }
+    if let Some(room) = config.rooms.iter().find(|room| {
+        room.carried
+            .as_ref()
+            .is_some_and(|carried| carried.len() > usize::from(room.enemies.min()))
+    }) {
+        return Err(LevelError::CarriedItems(room.id));
+    }
+    if config.rooms.iter().all(|room| room.enter.is_none()) {
+        return Err(LevelError::NoEntry);
+    }
+    Ok(())
+}
+
+/// Rooms with the rooms they're connected to: direction of the door, room and whether it's closed
+pub type RoomMap<'a> = BTreeMap<u8, Vec<(Direction, &'a RoomConfig, bool)>>;
+
+pub struct RoomGraph<'a> {
+    pub rooms: RoomMap<'a>,
+    pub entry: &'a RoomConfig,
+    pub enter: Direction,
+}
+
+pub fn build_room_graph(config: &LevelConfig) -> Result<RoomGraph<'_>, LevelError> {
+    let rooms = &config.rooms;
+    let room_map =
+        rooms
+            .iter()
+            .map(|room| {
+                (
+                    room.id,
+                    rooms
+                        .iter()
+                        .filter_map(|connected| {
+                            room.doors
+                                .iter()
+                                .find(|door| door.to == connected.id)
+                                .map(|door| (door.direction, connected, door.closed))
+                                .or_else(|| {
+                                    connected.doors.iter().find(|door| door.to == room.id).map(
+                                        |door| (door.direction.inverse(), connected, door.closed),
+                                    )
+                                })
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<BTreeMap<_, _>>();
+
+    let enters: Vec<_> = rooms
+        .iter()
+        .filter_map(|room| room.enter.map(|enter| (room, enter)))
+        .collect();
+    // Several entry rooms make the player start from a random one
+    let (entry, enter) = match enters.len() {
+        0 => return Err(LevelError::NoEntry),
+        len => enters[gen_range(0, len)],
+    };
+    Ok(RoomGraph {
+        rooms: room_map,
+        entry,
+        enter,
+    })
+}
+
+/// Random spawn position of the player entering through the wall in the direction
+pub fn entry_position(enter: Direction) -> Vec2 {
+    let randomed = gen_range(0.35, 0.65);
+    match enter {
+        Direction::North => Vec2 {
+            x: randomed + (RATIO_W_H - 1.) / 2.,
+            y: 0.1,
+        },
+        Direction::South => Vec2 {
+            x: randomed + (RATIO_W_H - 1.) / 2.,
+            y: 0.9,
+        },
+        Direction::West => Vec2 {
+            x: 0.1,
+            y: randomed,
+        },
+        Direction::East => Vec2 {
+            x: 0.9,
+            y: randomed,
+        },
+    }
 }
 
 pub fn push_room(
     rooms: &mut Vec<(u8, Vec<Enemy>, Vec<ItemCrate>)>,
     room: &RoomConfig,
-    room_map: &HashMap<&RoomConfig, Vec<(Direction, &RoomConfig, bool)>>,
+    room_map: &RoomMap,
+    difficulty: Difficulty,
 ) -> Option<usize> {
-    let mut connected_rooms = HashMap::new();
-    for (direction, room, _) in room_map.get(room).unwrap().iter().copied() {
+    // Ordered maps keep the order of spawning (and so of random numbers) the same between runs
+    let mut connected_rooms = BTreeMap::new();
+    for (direction, room, _) in room_map.get(&room.id).unwrap().iter().copied() {
         if connected_rooms.insert(direction, room).is_some() {
             return None;
         }
     }
     rooms.push((
         room.id,
-        (0..room.enemies)
-            .map(|_| {
+        (0..room.enemies.count(difficulty))
+            .map(|n| {
                 let position = Vec2 {
                     x: gen_range(RATIO_W_H / 3.0, 2. * RATIO_W_H / 3.),
                     y: gen_range(0.25, 0.75),
@@ -372,9 +1094,39 @@ pub fn push_room(
                     },
                     reload: Reload::default(),
                     state: EnemyState::Idle,
+                    alertness: 0.,
+                    alert_radius: room.alert_radius.unwrap_or(f32::INFINITY),
                     post: Post(position),
-                    health: Health::Low,
+                    health: if room.tough_enemies {
+                        Health::Low
+                    } else {
+                        Health::Critical
+                    },
+                    tough: room.tough_enemies,
+                    dead_time: 0.,
                     stain: None,
+                    stun: 0.,
+                    carries: room
+                        .carried
+                        .as_ref()
+                        .and_then(|carried| carried.get(n as usize))
+                        .cloned(),
+                    throws: room
+                        .throws
+                        .as_ref()
+                        .and_then(|throws| throws.get(n as usize))
+                        .cloned(),
+                    speed: room.enemy_speed.unwrap_or(1.),
+                    patrol: room
+                        .patrols
+                        .as_ref()
+                        .and_then(|patrols| patrols.get(n as usize))
+                        .map(|points| points.iter().map(|&point| Vec2::from(point)).collect())
+                        .unwrap_or_default(),
+                    waypoint: 0,
+                    route: Vec::new(),
+                    route_time: 0.,
+                    step_time: 0.,
                 }
             })
             .collect(),
@@ -400,15 +1152,48 @@ pub fn push_room(
         rooms
             .iter()
             .position(|r| r.0 == room.id)
-            .or_else(|| push_room(rooms, room, room_map))
+            .or_else(|| push_room(rooms, room, room_map, difficulty))
             .map(|_| ())
     })?;
     Some(room_pos)
 }
 
+/// How the battle went, scenes after it may depend on that
+#[derive(Default, Clone, Copy)]
+pub struct Outcome {
+    pub spotted: bool,
+    pub died: bool,
+    /// No guard was killed in the level
+    pub pacifist: bool,
+    /// Items besides the sword the player left with
+    pub loot: u8,
+}
+
 pub struct Level {
     pub level: LevelInner,
+    pub outcome: Outcome,
+    /// Seconds spent in the level
+    pub time: f32,
     backup: LevelInner,
+    camera: Camera,
+    last_stand: bool,
+    /// Seconds with low health before healing, `None` if the player doesn't heal
+    heal_time: Option<f32>,
+    /// Seconds since the player died
+    death_fade: f32,
+    /// Door the player looks through and the room behind it
+    peek: Option<(Direction, Room)>,
+    /// Path of the player since the last save, one point per tick
+    path: VecDeque<(Room, Vec2, Vec2)>,
+    /// Path of the previous life replayed after restart and its current tick, so it
+    /// plays at the speed it was walked at any frame rate
+    ghost: (Vec<(Room, Vec2, Vec2)>, usize),
+    show_minimap: bool,
+    show_captions: bool,
+    /// Config the level was built from, to start it over
+    config: LevelConfig,
+    /// Input waiting for the next tick
+    input: LevelInput,
 }
 
 #[derive(Clone)]
@@ -419,69 +1204,38 @@ pub struct LevelInner {
     doors: Vec<Door>,
     crates: Vec<ItemCrate>,
     stains: Vec<Stain>,
+    hazards: Vec<Hazard>,
+    decoys: Vec<Decoy>,
+    slow_motion: f32,
+    last_stands: Vec<Room>,
+    /// Rooms shown on the minimap
+    visited: HashSet<u8>,
+    /// Recent phrases, the newest last
+    captions: VecDeque<Caption>,
+    leave_alive: bool,
 }
 
 impl Level {
-    pub fn load(config: &LevelConfig) -> Self {
+    pub fn load(config: &LevelConfig, settings: &Settings) -> Self {
+        if let Some(seed) = settings.seed.or(config.seed) {
+            macroquad::rand::srand(seed);
+        }
+        let RoomGraph {
+            rooms: room_map,
+            entry: entry_room,
+            enter,
+        } = build_room_graph(config).unwrap_or_else(|err| panic!("{err}"));
+        let position = entry_position(enter);
         let rooms = &config.rooms;
-        let room_map = rooms
-            .iter()
-            .map(|room| {
-                (
-                    room,
-                    rooms
-                        .iter()
-                        .filter_map(|connected| {
-                            room.doors
-                                .iter()
-                                .find(|door| door.to == connected.id)
-                                .map(|door| (door.direction, connected, door.closed))
-                                .or_else(|| {
-                                    connected.doors.iter().find(|door| door.to == room.id).map(
-                                        |door| (door.direction.inverse(), connected, door.closed),
-                                    )
-                                })
-                        })
-                        .collect::<Vec<_>>(),
-                )
-            })
-            .collect::<HashMap<_, _>>();
-
-        let mut enters: Vec<_> = rooms.iter().filter(|room| room.enter.is_some()).collect();
-        let entry_room = match enters.len() {
-            1 => enters.pop().unwrap(),
-            // 0 => return Err(Error::NoEntry),
-            // _ => {
-            //     return Err(Error::MoreThanOneEntry(
-            //         enters.iter().map(|room| room.id).collect(),
-            //     ))
-            // }
-            _ => panic!("not one enter"),
-        };
-        let Some(enter) = entry_room.enter else {
-                    unreachable!()
-                };
-        let randomed = gen_range(0.35, 0.65);
-        let position = match enter {
-            Direction::North => Vec2 {
-                x: randomed + (RATIO_W_H - 1.) / 2.,
-                y: 0.1,
-            },
-            Direction::South => Vec2 {
-                x: randomed + (RATIO_W_H - 1.) / 2.,
-                y: 0.9,
-            },
-            Direction::West => Vec2 {
-                x: 0.1,
-                y: randomed,
-            },
-            Direction::East => Vec2 {
-                x: 0.9,
-                y: randomed,
-            },
-        };
         let mut result_rooms = Vec::with_capacity(rooms.len());
-        let current_room = push_room(&mut result_rooms, entry_room, &room_map).unwrap() as u8;
+        push_room(
+            &mut result_rooms,
+            entry_room,
+            &room_map,
+            settings.difficulty,
+        )
+        .unwrap();
+        let current_room = entry_room.id;
         let player = Player {
             body: Body {
                 position: Position(position),
@@ -496,9 +1250,15 @@ impl Level {
             },
             reload: Reload::default(),
             health: Health::Full,
-            item: Item::Sword,
+            inventory: Inventory::new(Item::Sword),
             visible: false,
-            heal_time: HEAL_TIME,
+            heal_time: 0.,
+            bleed_time: 0.,
+            hazard_time: 0.,
+            step_time: 0.,
+            stamina: 1.,
+            exhausted: false,
+            sprinting: false,
         };
         let mut enemies = Vec::new();
         let mut crates = Vec::new();
@@ -514,8 +1274,9 @@ impl Level {
                     Room(from),
                     Room(door.to),
                     door.direction,
-                    door.closed,
+                    door.closed || door.breakable,
                     false,
+                    door.breakable,
                 )
             })
             .collect();
@@ -525,7 +1286,18 @@ impl Level {
             enter,
             false,
             true,
+            false,
         ));
+        let hazards = rooms
+            .iter()
+            .flat_map(|room| {
+                room.hazards.iter().flatten().map(|hazard| Hazard {
+                    rect: Rect::new(hazard.x, hazard.y, hazard.width, hazard.height),
+                    kind: hazard.kind,
+                    room: Room(room.id),
+                })
+            })
+            .collect();
         let inner = LevelInner {
             player,
             enemies,
@@ -533,19 +1305,72 @@ impl Level {
             stains: Vec::new(),
             doors,
             crates,
+            hazards,
+            decoys: Vec::new(),
+            slow_motion: 0.,
+            last_stands: Vec::new(),
+            visited: HashSet::from([current_room]),
+            captions: VecDeque::new(),
+            leave_alive: config.leave_alive,
         };
         Self {
             backup: inner.clone(),
             level: inner,
+            outcome: Outcome {
+                pacifist: true,
+                ..Default::default()
+            },
+            time: 0.,
+            camera: Camera::new(config.intro, settings.zoom),
+            last_stand: config.last_stand,
+            heal_time: config
+                .heal
+                .unwrap_or(settings.heal.enabled)
+                .then(|| config.heal_time.unwrap_or(settings.heal.time)),
+            death_fade: 0.,
+            peek: None,
+            path: VecDeque::new(),
+            ghost: (Vec::new(), 0),
+            show_minimap: true,
+            show_captions: settings.captions,
+            config: config.clone(),
+            input: LevelInput::default(),
         }
     }
 }
 
+/// Recent phrases in the bottom left corner, fading out before they expire
+fn draw_captions(screen: &Screen, captions: &VecDeque<Caption>) {
+    let line = 0.04;
+    let bottom = 0.9;
+    for (n, caption) in captions.iter().rev().enumerate() {
+        let speaker = match caption.speaker {
+            None => "You".to_owned(),
+            Some(n) => format!("Guard {}", n + 1),
+        };
+        let text = format!("{speaker}: {}", caption.text);
+        let y = bottom - n as f32 * line;
+        let alpha = caption.time.min(1.);
+        draw_rect(
+            screen,
+            0.02,
+            y - line + 0.008,
+            0.02 + get_lines(screen, RATIO_W_H, 0.03, &text).1,
+            line,
+            Color::from_rgba(0, 0, 0, (128. * alpha) as u8),
+        );
+        draw_txt(screen, &text, 0.03, y, 0.03, Color { a: alpha, ..WHITE });
+    }
+}
+
 fn player_action(
-    screen: &Screen,
+    input: &PlayerInput,
     player: &mut Player,
     balls: &mut Vec<Ball>,
-    assets: &Assets,
+    sounds: &mut Vec<SoundEvent>,
+    settings: &Settings,
+    // Seconds to heal, `None` while the player doesn't heal
+    heal_time: Option<f32>,
     dt: f32,
 ) -> MoveAction {
     if player.health == Health::Dead {
@@ -553,39 +1378,22 @@ fn player_action(
             width: 1.5 * PLAYER_RADIUS,
             height: 0.9 * PLAYER_RADIUS,
         };
+        player.sprinting = false;
         return MoveAction::default();
     }
-    let mut move_direction = (0, 0);
-    if is_key_down(KeyCode::W) || is_key_down(KeyCode::Up) {
-        move_direction.1 -= 1;
-    }
-    if is_key_down(KeyCode::S) || is_key_down(KeyCode::Down) {
-        move_direction.1 += 1;
-    }
-    if is_key_down(KeyCode::A) || is_key_down(KeyCode::Left) {
-        move_direction.0 -= 1;
+    if let Some(slot) = input
+        .slot
+        .filter(|&slot| slot < player.inventory.items.len())
+    {
+        player.inventory.selected = slot;
     }
-    if is_key_down(KeyCode::D) || is_key_down(KeyCode::Right) {
-        move_direction.0 += 1;
+    if input.cycle != 0 {
+        player.inventory.cycle(input.cycle);
     }
-    let (x_mouse, y_mouse) = {
-        let (x_m, y_m) = mouse_position();
-        (
-            clamp((x_m - screen.x) / screen.height, 0., RATIO_W_H),
-            clamp((y_m - screen.y) / screen.height, 0., 1.),
-        )
-    };
-    let sight = Vec2 {
-        x: x_mouse - player.body.position.0.x,
-        y: y_mouse - player.body.position.0.y,
-    }
-    .normalize_or_zero();
-    let move_action = MoveAction {
-        move_direction,
-        sight,
-    };
+    let move_action = input.move_action;
+    let move_direction = move_action.move_direction;
 
-    if is_key_pressed(KeyCode::Space) {
+    if input.crouch {
         player.body.form = if player.visible {
             player.visible = false;
             Form::Rect {
@@ -600,42 +1408,390 @@ fn player_action(
             }
         };
     }
-    if is_mouse_button_down(MouseButton::Left)
-        && (player.visible || cfg!(feature = "cheat"))
-        && player.reload.0 == 0.
-    {
-        match player.item {
-            Item::Vegetable { .. } => {
+    // Only standing players sprint
+    player.sprinting =
+        input.sprint && player.visible && !player.exhausted && move_direction != (0, 0);
+    if player.sprinting {
+        player.stamina -= dt / SPRINT_TIME;
+        if player.stamina <= 0. {
+            player.stamina = 0.;
+            player.exhausted = true;
+        }
+    } else {
+        player.stamina = f32::min(player.stamina + dt / STAMINA_RECOVERY_TIME, 1.);
+        if player.stamina >= STAMINA_THRESHOLD {
+            player.exhausted = false;
+        }
+    }
+    if input.attack && (player.visible || cfg!(feature = "cheat")) && player.reload.0 == 0. {
+        match player.inventory.active_mut() {
+            Item::Vegetable { count: 0, .. } => {
+                player.body.phrase = Some(Phrase {
+                    text: "Out of vegetables".to_owned(),
+                    time: 1.,
+                });
+            }
+            Item::Vegetable { count, .. } => {
+                *count -= 1;
                 player.reload.0 = PLAYER_RELOAD;
                 let position = player.body.position.0 + (move_action.sight * PLAYER_RADIUS);
                 balls.push(Ball {
                     position: Position(position),
-                    velocity: Velocity(move_action.sight * BALL_SPEED),
+                    radius: player.inventory.active().ball_radius(),
+                    velocity: Velocity(move_action.sight * player.inventory.active().ball_speed()),
+                    lifetime: 0.,
                     room: player.body.room,
-                    item: player.item.clone(),
+                    item: player.inventory.active().clone(),
+                    hostile: false,
+                });
+                sounds.push(SoundEvent::new("throw", settings.sfx_volume()));
+            }
+            Item::Bandage if matches!(player.health, Health::Low | Health::Critical) => {
+                if player.inventory.drop().is_some() {
+                    player.health.increase();
+                    player.heal_time = 0.;
+                    player.bleed_time = 0.;
+                    sounds.push(SoundEvent::new("item", settings.sfx_volume()));
+                }
+            }
+            Item::Bandage => {
+                player.body.phrase = Some(Phrase {
+                    text: "I'm not hurt".to_owned(),
+                    time: 1.,
                 });
-                play_sound_once(assets.sounds["throw"]);
             }
             _ => {
                 player.body.phrase = Some(Phrase {
-                    text: format!("I can't attack with {}", player.item.name()),
+                    text: format!("I can't attack with {}", player.inventory.active().name()),
                     time: 3.,
                 });
             }
         }
     }
-    if player.health == Health::Low {
-        player.heal_time -= dt;
-        if player.heal_time <= 0. {
-            player.heal_time = HEAL_TIME;
+    if let (Health::Low, Some(heal_time)) = (&player.health, heal_time) {
+        player.heal_time += dt;
+        if player.heal_time >= heal_time {
+            player.heal_time = 0.;
             player.health = Health::Full;
         }
     }
+    // Critical wounds don't heal
+    if player.health == Health::Critical {
+        player.bleed_time += dt;
+        if player.bleed_time >= BLEED_TIME {
+            player.bleed_time = 0.;
+            player.health = Health::Dead;
+        }
+    }
 
     move_action
 }
 
-fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32) -> MoveAction {
+/// Whether the segment crosses the rectangle with the given center and half size
+fn segment_hits_rect(from: Vec2, to: Vec2, center: Vec2, half: Vec2) -> bool {
+    segment_hit_time(from, to, center, half).is_some()
+}
+
+/// Part of the segment passed before it enters the rectangle with the given center and
+/// half size, 0 when it starts inside
+fn segment_hit_time(from: Vec2, to: Vec2, center: Vec2, half: Vec2) -> Option<f32> {
+    let direction = to - from;
+    let (mut t_min, mut t_max) = (0f32, 1f32);
+    for (start, delta, min, max) in [
+        (from.x, direction.x, center.x - half.x, center.x + half.x),
+        (from.y, direction.y, center.y - half.y, center.y + half.y),
+    ] {
+        if delta.abs() < f32::EPSILON {
+            if start < min || start > max {
+                return None;
+            }
+        } else {
+            let (t1, t2) = ((min - start) / delta, (max - start) / delta);
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+            if t_min > t_max {
+                return None;
+            }
+        }
+    }
+    Some(t_min)
+}
+
+/// Part of the ball's path passed before it touches the body
+fn ball_hit_time(from: Vec2, to: Vec2, radius: f32, body: &Body) -> Option<f32> {
+    let half = Vec2::new(body.form.x_r() + radius, body.form.y_r() + radius);
+    segment_hit_time(from, to, body.position.0, half)
+}
+
+/// Part of the ball's path passed before it leaves the room, 1 if it stays inside
+fn ball_wall_time(from: Vec2, to: Vec2, radius: f32) -> f32 {
+    let direction = to - from;
+    [(from.x, direction.x, RATIO_W_H), (from.y, direction.y, 1.)]
+        .into_iter()
+        .map(|(start, delta, size)| {
+            if delta > f32::EPSILON {
+                (size - WALL_SIZE - radius - start) / delta
+            } else if delta < -f32::EPSILON {
+                (WALL_SIZE + radius - start) / delta
+            } else {
+                1.
+            }
+        })
+        .fold(1., f32::min)
+        .max(0.)
+}
+
+/// Whether the body sees the target: in front of it, close enough and not behind a crate
+pub fn in_view(body: &Body, target: Vec2, fov_deg: f32, range: f32, crates: &[ItemCrate]) -> bool {
+    let diff = target - body.position.0;
+    if diff.length() > range {
+        return false;
+    }
+    if diff.length() > f32::EPSILON
+        && body.sight.0.length() > f32::EPSILON
+        && body.sight.0.angle_between(diff).abs() > fov_deg.to_radians() / 2.
+    {
+        return false;
+    }
+    !crates.iter().any(|item_crate| {
+        item_crate.room == body.room
+            && segment_hits_rect(
+                body.position.0,
+                target,
+                item_crate.position.0,
+                Vec2::new(item_crate.form.x_r(), item_crate.form.y_r()),
+            )
+    })
+}
+
+/// Cells on the grid of the room, the border is taken by the walls
+fn grid_size() -> (i32, i32) {
+    (
+        ((RATIO_W_H - 2. * WALL_SIZE) / GRID_CELL) as i32,
+        ((1. - 2. * WALL_SIZE) / GRID_CELL) as i32,
+    )
+}
+
+fn cell_center((x, y): (i32, i32)) -> Vec2 {
+    Vec2::new(
+        WALL_SIZE + (x as f32 + 0.5) * GRID_CELL,
+        WALL_SIZE + (y as f32 + 0.5) * GRID_CELL,
+    )
+}
+
+fn cell_of(position: Vec2) -> (i32, i32) {
+    let (width, height) = grid_size();
+    (
+        clamp(((position.x - WALL_SIZE) / GRID_CELL) as i32, 0, width - 1),
+        clamp(((position.y - WALL_SIZE) / GRID_CELL) as i32, 0, height - 1),
+    )
+}
+
+/// A* over the grid of the room, returns waypoints without the start cell
+fn find_path(
+    from: Vec2,
+    to: Vec2,
+    form: Form,
+    crates: &[ItemCrate],
+    room: Room,
+) -> Option<Vec<Vec2>> {
+    let (width, height) = grid_size();
+    let blocked = |cell: (i32, i32)| {
+        let center = cell_center(cell);
+        crates.iter().any(|item_crate| {
+            let diff = center - item_crate.position.0;
+            item_crate.room == room
+                && (diff.length() < f32::EPSILON
+                    || diff.length()
+                        < form.direction_len(diff) + item_crate.form.direction_len(diff))
+        })
+    };
+    let start = cell_of(from);
+    let mut goal = cell_of(to);
+    if start == goal {
+        return Some(Vec::new());
+    }
+    // Target inside a crate, go to the closest free cell
+    if blocked(goal) {
+        goal = (0..width)
+            .flat_map(|x| (0..height).map(move |y| (x, y)))
+            .filter(|cell| !blocked(*cell))
+            .min_by(|a, b| {
+                cell_center(*a)
+                    .distance(to)
+                    .partial_cmp(&cell_center(*b).distance(to))
+                    .unwrap_or(Ordering::Equal)
+            })?;
+    }
+    // Octile distance with 10 for a straight and 14 for a diagonal step
+    let heuristic = |(x, y): (i32, i32)| {
+        let (dx, dy) = ((x - goal.0).abs(), (y - goal.1).abs());
+        10 * dx.max(dy) + 4 * dx.min(dy)
+    };
+    let mut costs = HashMap::from([(start, 0)]);
+    let mut came_from = HashMap::new();
+    let mut open = BinaryHeap::from([Reverse((heuristic(start), start))]);
+    // Cells already expanded with their cheapest cost, stale heap entries are skipped
+    let mut closed = HashSet::new();
+    while let Some(Reverse((_, cell))) = open.pop() {
+        if !closed.insert(cell) {
+            continue;
+        }
+        if cell == goal {
+            let mut path = vec![cell_center(cell)];
+            let mut current = cell;
+            while let Some(&previous) = came_from.get(&current) {
+                if previous == start {
+                    break;
+                }
+                path.push(cell_center(previous));
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for (dx, dy) in [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ] {
+            let next = (cell.0 + dx, cell.1 + dy);
+            if next.0 < 0
+                || next.1 < 0
+                || next.0 >= width
+                || next.1 >= height
+                || closed.contains(&next)
+                || blocked(next)
+            {
+                continue;
+            }
+            // Don't cut corners of crates
+            if dx != 0
+                && dy != 0
+                && (blocked((cell.0 + dx, cell.1)) || blocked((cell.0, cell.1 + dy)))
+            {
+                continue;
+            }
+            let cost = costs[&cell] + if dx != 0 && dy != 0 { 14 } else { 10 };
+            if costs.get(&next).is_none_or(|&old| cost < old) {
+                costs.insert(next, cost);
+                came_from.insert(next, cell);
+                open.push(Reverse((cost + heuristic(next), next)));
+            }
+        }
+    }
+    None
+}
+
+/// Next step of the guard towards the target around the crates
+fn path_step(enemy: &mut Enemy, to: Vec2, crates: &[ItemCrate], dt: f32) -> (i32, i32) {
+    enemy.route_time -= dt;
+    if enemy.route_time <= 0. {
+        enemy.route_time = ROUTE_TIME;
+        enemy.route = find_path(
+            enemy.body.position.0,
+            to,
+            enemy.body.form,
+            crates,
+            enemy.body.room,
+        )
+        .unwrap_or_default();
+    }
+    while enemy
+        .route
+        .first()
+        .is_some_and(|waypoint| enemy.body.position.0.distance(*waypoint) < 1.5 * PLAYER_RADIUS)
+    {
+        enemy.route.remove(0);
+    }
+    // Without a route go straight and slide along the crates in the way
+    match enemy.route.first() {
+        Some(waypoint) => enemy.body.position.move_to(*waypoint),
+        None => avoid_crates(&enemy.body, to, crates),
+    }
+}
+
+/// How deep the body at the position would be in the crates of its room
+fn crate_penetration(body: &Body, position: Vec2, crates: &[ItemCrate]) -> f32 {
+    crates
+        .iter()
+        .filter(|item_crate| item_crate.room == body.room)
+        .map(|item_crate| {
+            let diff = position - item_crate.position.0;
+            if diff.length() < f32::EPSILON {
+                return body.form.x_r() + item_crate.form.x_r();
+            }
+            (body.form.direction_len(diff) + item_crate.form.direction_len(diff) - diff.length())
+                .max(0.)
+        })
+        .sum()
+}
+
+/// Straight step to the target, turned aside when it would push into a crate
+fn avoid_crates(body: &Body, to: Vec2, crates: &[ItemCrate]) -> (i32, i32) {
+    let direction = body.position.move_to(to);
+    let probe = |(x, y): (i32, i32)| {
+        let step = Vec2::new(x as f32, y as f32).normalize_or_zero() * PLAYER_RADIUS;
+        crate_penetration(body, body.position.0 + step, crates)
+    };
+    let current = crate_penetration(body, body.position.0, crates);
+    if direction == (0, 0) || probe(direction) <= current {
+        return direction;
+    }
+    let (x, y) = direction;
+    [(-y, x), (y, -x)]
+        .into_iter()
+        .filter(|side| probe(*side) <= current)
+        .min_by(|a, b| {
+            let distance = |(x, y): (i32, i32)| {
+                let step = Vec2::new(x as f32, y as f32).normalize_or_zero() * PLAYER_RADIUS;
+                (body.position.0 + step).distance(to)
+            };
+            distance(*a).total_cmp(&distance(*b))
+        })
+        .unwrap_or(direction)
+}
+
+/// Things around the guards they react to
+struct Surroundings<'a> {
+    decoys: &'a [Decoy],
+    crates: &'a [ItemCrate],
+    doors: &'a [Door],
+}
+
+/// Whether nothing is in between, the recess of a closed door covers whoever stands in it
+pub fn can_see(from: Vec2, to: Vec2, doors: &[Door], room: Room) -> bool {
+    !doors.iter().any(|door| {
+        let Some((direction, _)) = door.door_from(&room) else {
+            return false;
+        };
+        let (x_range, y_range) = door_area(direction);
+        let center = Vec2::new(
+            (x_range.start() + x_range.end()) / 2.,
+            (y_range.start() + y_range.end()) / 2.,
+        );
+        let half = Vec2::new(
+            (x_range.end() - x_range.start()) / 2.,
+            (y_range.end() - y_range.start()) / 2.,
+        );
+        door.closed && !door.entrance && segment_hits_rect(from, to, center, half)
+    })
+}
+
+fn enemy_action(
+    enemy: &mut Enemy,
+    player: &mut Player,
+    surroundings: &Surroundings,
+    balls: &mut Vec<Ball>,
+    sounds: &mut Vec<SoundEvent>,
+    settings: &Settings,
+    dt: f32,
+) -> MoveAction {
     if enemy.health == Health::Dead {
         enemy.body.form = Form::Rect {
             width: 1.7 * PLAYER_RADIUS,
@@ -643,21 +1799,65 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
         };
         return MoveAction::default();
     }
+    if enemy.stun > 0. {
+        enemy.stun -= dt;
+        return MoveAction {
+            move_direction: (0, 0),
+            sight: enemy.body.sight.0,
+        };
+    }
     let diff = enemy.body.position.0 - player.body.position.0;
-    let touch_distance = if player.health == Health::Full {
-        SLASH_LEN / 2.
+    let detection = settings.difficulty.params().detection;
+    let touch_distance = detection
+        * match player.health {
+            Health::Full => SLASH_LEN / 2.,
+            Health::Low => SLASH_LEN / 6.,
+            Health::Critical | Health::Dead => SLASH_LEN / 10.,
+        };
+    let touching = diff.length()
+        < enemy.body.form.direction_len(diff)
+            + player.body.form.direction_len(diff)
+            + touch_distance;
+    let Surroundings {
+        decoys,
+        crates,
+        doors,
+    } = surroundings;
+    let noticed = player.health != Health::Dead
+        && player.body.room == enemy.body.room
+        && (touching
+            || player.visible
+                && in_view(
+                    &enemy.body,
+                    player.body.position.0,
+                    settings.enemy_fov,
+                    detection * settings.enemy_view_range,
+                    crates,
+                )
+                && can_see(
+                    enemy.body.position.0,
+                    player.body.position.0,
+                    doors,
+                    enemy.body.room,
+                ));
+    enemy.alertness = if !noticed {
+        clamp(enemy.alertness - dt / ALERT_DECAY_TIME, 0., 1.)
+    } else if touching
+        || matches!(
+            enemy.state,
+            EnemyState::Fight(_, _) | EnemyState::LastSeen(_, _)
+        )
+    {
+        1.
     } else {
-        SLASH_LEN / 6.
+        // The closer the player is, the faster the guard realizes what's going on
+        let closeness = clamp(1. - diff.length() / RATIO_W_H, 0.2, 1.);
+        clamp(enemy.alertness + closeness * dt / ALERT_TIME, 0., 1.)
     };
-    let player_visible = player.visible
-        || diff.length()
-            < enemy.body.form.direction_len(diff)
-                + player.body.form.direction_len(diff)
-                + touch_distance;
     let mut phrase = None;
     enemy.state = if player.health == Health::Dead {
         EnemyState::Idle
-    } else if player.body.room == enemy.body.room && player_visible {
+    } else if noticed && enemy.alertness >= 1. {
         if !matches!(enemy.state, EnemyState::Fight(_, _)) {
             phrase = Some(Phrase {
                 text: "Here you are!".to_owned(),
@@ -665,6 +1865,26 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
             });
         }
         EnemyState::Fight(player.body.position.0, player.body.form)
+    } else if noticed {
+        if !matches!(enemy.state, EnemyState::Suspicious(_)) {
+            phrase = Some(Phrase {
+                text: "Hm?".to_owned(),
+                time: 1.,
+            });
+        }
+        EnemyState::Suspicious(player.body.position.0)
+    } else if let Some(decoy) = decoys.iter().find(|decoy| {
+        matches!(enemy.state, EnemyState::Idle | EnemyState::LastSeen(_, _))
+            && decoy.room == enemy.body.room
+            && decoy.position.0.distance(enemy.body.position.0) <= DECOY_RANGE
+    }) {
+        if matches!(enemy.state, EnemyState::Idle) {
+            phrase = Some(Phrase {
+                text: "What was that?".to_owned(),
+                time: 1.,
+            });
+        }
+        EnemyState::LastSeen(decoy.position.0, 0.)
     } else {
         match enemy.state {
             EnemyState::Fight(position, _) => {
@@ -674,6 +1894,13 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
                 });
                 EnemyState::LastSeen(position, dt)
             }
+            EnemyState::Suspicious(position) => {
+                if enemy.alertness > 0. {
+                    EnemyState::Suspicious(position)
+                } else {
+                    EnemyState::Idle
+                }
+            }
             EnemyState::Idle => EnemyState::Idle,
             EnemyState::LastSeen(position, timer) => {
                 let new_timer = timer + dt;
@@ -693,9 +1920,25 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
         enemy.body.phrase = Some(phrase);
     }
     let (move_action, slash) = match enemy.state {
+        EnemyState::Idle if !enemy.patrol.is_empty() => {
+            let mut waypoint = enemy.patrol[enemy.waypoint];
+            if enemy.body.position.0.distance(waypoint) < 1.5 * PLAYER_RADIUS {
+                enemy.waypoint = (enemy.waypoint + 1) % enemy.patrol.len();
+                waypoint = enemy.patrol[enemy.waypoint];
+            }
+            (
+                MoveAction {
+                    move_direction: avoid_crates(&enemy.body, waypoint, crates),
+                    sight: (waypoint - enemy.body.position.0)
+                        .try_normalize()
+                        .unwrap_or(enemy.body.sight.0),
+                },
+                false,
+            )
+        }
         EnemyState::Idle => (
             MoveAction {
-                move_direction: enemy.body.position.move_to(enemy.post.0),
+                move_direction: avoid_crates(&enemy.body, enemy.post.0, crates),
                 sight: Vec2 { x: 1., y: 0. },
             },
             false,
@@ -704,7 +1947,7 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
             let diff = player_position - enemy.body.position.0;
             (
                 MoveAction {
-                    move_direction: enemy.body.position.move_to(player_position),
+                    move_direction: path_step(enemy, player_position, crates, dt),
                     sight: (player_position - enemy.body.position.0).normalize(),
                 },
                 diff.length()
@@ -713,18 +1956,53 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
                         + SLASH_LEN,
             )
         }
+        EnemyState::Suspicious(position) => (
+            MoveAction {
+                move_direction: (0, 0),
+                sight: (position - enemy.body.position.0).normalize_or_zero(),
+            },
+            false,
+        ),
         EnemyState::LastSeen(last_position, _) => (
             MoveAction {
-                move_direction: enemy.body.position.move_to(last_position),
-                sight: last_position - enemy.body.position.0,
+                move_direction: path_step(enemy, last_position, crates, dt),
+                sight: (last_position - enemy.body.position.0)
+                    .try_normalize()
+                    .unwrap_or(enemy.body.sight.0),
             },
             false,
         ),
     };
+    let params = settings.difficulty.params();
     if slash && enemy.reload.0 == 0. {
-        enemy.reload.0 = PLAYER_RELOAD;
+        enemy.reload.0 = params.enemy_reload;
         player.health.decrease();
-        play_sound_once(assets.sounds["sword"]);
+        sounds.push(SoundEvent::new("sword", settings.sfx_volume()));
+    } else if let (EnemyState::Fight(player_position, _), Some(item)) =
+        (&enemy.state, &enemy.throws)
+    {
+        if !slash
+            && enemy.reload.0 == 0.
+            && enemy.body.position.0.distance(*player_position) <= ENEMY_THROW_RANGE
+        {
+            enemy.reload.0 = params.enemy_throw_reload;
+            balls.push(Ball {
+                position: Position(enemy.body.position.0 + move_action.sight * PLAYER_RADIUS),
+                radius: item.ball_radius(),
+                velocity: Velocity(move_action.sight * item.ball_speed()),
+                lifetime: 0.,
+                room: enemy.body.room,
+                item: item.clone(),
+                hostile: true,
+            });
+            positional_sound(
+                sounds,
+                "throw",
+                settings.sfx_volume(),
+                (enemy.body.room, enemy.body.position.0),
+                &player.body,
+            );
+        }
     }
     enemy.body.form = if enemy.reload.0 < 0.2 {
         Form::Rect {
@@ -740,6 +2018,95 @@ fn enemy_action(enemy: &mut Enemy, player: &mut Player, assets: &Assets, dt: f32
     move_action
 }
 
+/// Advances the footstep timer of a body by its speed, returns `true` when a step is made
+fn footstep(body: &Body, step_time: &mut f32, interval: f32, dt: f32) -> bool {
+    let speed = Vec2::new(body.speed.x as f32, body.speed.y as f32).length() / SPEED_STEPS as f32;
+    if speed == 0. {
+        *step_time = 0.;
+        return false;
+    }
+    *step_time += speed.min(1.) * dt;
+    if *step_time >= interval {
+        *step_time = 0.;
+        true
+    } else {
+        false
+    }
+}
+
+/// Guards that hear a shout come to check the place: shouts are room, position of the one
+/// shouting and the place to check
+fn alert_allies(enemies: &mut [Enemy], shouts: &[(Room, Vec2, Vec2)]) {
+    for enemy in enemies {
+        if enemy.health == Health::Dead
+            || matches!(
+                enemy.state,
+                EnemyState::Fight(_, _) | EnemyState::LastSeen(_, _)
+            )
+        {
+            continue;
+        }
+        let heard = shouts.iter().find(|(room, position, _)| {
+            *room == enemy.body.room
+                && position.distance(enemy.body.position.0) <= enemy.alert_radius
+        });
+        if let Some((_, _, target)) = heard {
+            enemy.state = EnemyState::LastSeen(*target, 0.);
+            enemy.body.phrase = Some(Phrase {
+                text: "What's there?".to_owned(),
+                time: 1.,
+            });
+        }
+    }
+}
+
+/// Guards that hear a noise come to check the place without seeing anything
+fn hear_noise(enemies: &mut [Enemy], room: Room, position: Vec2, radius: f32) {
+    for enemy in enemies {
+        if enemy.health == Health::Dead
+            || enemy.body.room != room
+            || enemy.body.position.0.distance(position) > radius
+            || matches!(
+                enemy.state,
+                EnemyState::Fight(_, _) | EnemyState::LastSeen(_, _)
+            )
+        {
+            continue;
+        }
+        enemy.state = EnemyState::LastSeen(position, 0.);
+        enemy.body.phrase = Some(Phrase {
+            text: "Footsteps?".to_owned(),
+            time: 1.,
+        });
+    }
+}
+
+/// Moves the body for one tick, speeding up towards the direction and slowing down without one.
+/// `scale` is the part of the player's top speed
+fn move_body(body: &mut Body, (x, y): (i32, i32), scale: f32, dt: f32) {
+    body.speed.x += 2 * x;
+    body.speed.y += 2 * y;
+
+    match body.speed.x.cmp(&0) {
+        std::cmp::Ordering::Less => body.speed.x += 1,
+        std::cmp::Ordering::Greater => body.speed.x -= 1,
+        _ => {}
+    }
+    body.speed.x = clamp(body.speed.x, -SPEED_STEPS, SPEED_STEPS);
+    match body.speed.y.cmp(&0) {
+        std::cmp::Ordering::Less => body.speed.y += 1,
+        std::cmp::Ordering::Greater => body.speed.y -= 1,
+        _ => {}
+    }
+    body.speed.y = clamp(body.speed.y, -SPEED_STEPS, SPEED_STEPS);
+    let speed = Vec2::new(
+        body.speed.x as f32 / SPEED_STEPS as f32,
+        body.speed.y as f32 / SPEED_STEPS as f32,
+    )
+    .clamp_length_max(1.);
+    body.position.0 += scale * PLAYER_MAX_SPEED * speed * dt;
+}
+
 fn collide(mut bodies: Vec<&mut Body>, crates: &Vec<ItemCrate>) {
     let mut shifts = HashMap::new();
     for (left_id, left) in bodies.iter().enumerate() {
@@ -753,7 +2120,8 @@ fn collide(mut bodies: Vec<&mut Body>, crates: &Vec<ItemCrate>) {
             let penetration = size - diff.length();
 
             if penetration > 0. {
-                let shift = diff.normalize() * penetration;
+                // A body right on top of a crate is pushed out sideways
+                let shift = diff.try_normalize().unwrap_or(Vec2::X) * penetration;
                 shifts
                     .entry(left_id)
                     .and_modify(|v| *v += shift)
@@ -772,7 +2140,13 @@ fn collide(mut bodies: Vec<&mut Body>, crates: &Vec<ItemCrate>) {
             let penetration = (size - diff.length()) / 2.;
 
             if penetration > 0. {
-                let shift = diff.normalize() * penetration;
+                // Bodies right on top of each other are pushed apart sideways
+                let side = if left_id < right_id {
+                    Vec2::X
+                } else {
+                    -Vec2::X
+                };
+                let shift = diff.try_normalize().unwrap_or(side) * penetration;
                 shifts
                     .entry(left_id)
                     .and_modify(|v| *v += shift)
@@ -785,7 +2159,8 @@ fn collide(mut bodies: Vec<&mut Body>, crates: &Vec<ItemCrate>) {
         }
     }
     for (id, body) in bodies.iter_mut().enumerate() {
-        body.position.0 += shifts[&id];
+        // Shifts of a body wedged between several others add up, don't let them fling it
+        body.position.0 += shifts[&id].clamp_length_max(MAX_COLLISION_SHIFT);
         let x_wall = body.form.x_r();
         let y_wall = body.form.y_r();
         body.position.0.x = clamp(
@@ -801,32 +2176,61 @@ fn collide(mut bodies: Vec<&mut Body>, crates: &Vec<ItemCrate>) {
     }
 }
 
-fn use_door(player: &mut Player, door: &mut Door, enemies: &Vec<Enemy>, assets: &Assets) -> bool {
+/// Area near the wall where the door in that direction is
+fn door_area(direction: Direction) -> (RangeInclusive<f32>, RangeInclusive<f32>) {
+    match direction {
+        Direction::North => (
+            (RATIO_W_H / 2. - 0.15..=RATIO_W_H / 2. + 0.15),
+            (0.0..=WALL_SIZE + 0.05),
+        ),
+        Direction::South => (
+            (RATIO_W_H / 2. - 0.15..=RATIO_W_H / 2. + 0.15),
+            ((1.0 - WALL_SIZE - 0.05)..=1.0),
+        ),
+        Direction::East => (((RATIO_W_H - WALL_SIZE - 0.05)..=RATIO_W_H), (0.35..=0.65)),
+        Direction::West => ((0.0..=(WALL_SIZE + 0.05)), (0.35..=0.65)),
+    }
+}
+
+/// Room behind the door the player stands at
+fn peek_through(player: &Player, doors: &[Door]) -> Option<(Direction, Room)> {
+    doors
+        .iter()
+        .filter(|door| !door.entrance)
+        .filter_map(|door| door.door_from(&player.body.room))
+        .find(|(direction, _)| {
+            let (x_range, y_range) = door_area(*direction);
+            x_range.contains(&player.body.position.0.x)
+                && y_range.contains(&player.body.position.0.y)
+        })
+}
+
+fn use_door(
+    player: &mut Player,
+    door: &mut Door,
+    enemies: &Vec<Enemy>,
+    visited: &mut HashSet<u8>,
+    sounds: &mut Vec<SoundEvent>,
+    settings: &Settings,
+    leave_alive: bool,
+) -> bool {
     if let Some((direction, to)) = door.door_from(&player.body.room) {
-        let (x_range, y_range) = match direction {
-            Direction::North => (
-                (RATIO_W_H / 2. - 0.15..=RATIO_W_H / 2. + 0.15),
-                (0.0..=WALL_SIZE + 0.05),
-            ),
-            Direction::South => (
-                (RATIO_W_H / 2. - 0.15..=RATIO_W_H / 2. + 0.15),
-                ((1.0 - WALL_SIZE - 0.05)..=1.0),
-            ),
-            Direction::East => (((RATIO_W_H - WALL_SIZE - 0.05)..=RATIO_W_H), (0.35..=0.65)),
-            Direction::West => ((0.0..=(WALL_SIZE + 0.05)), (0.35..=0.65)),
-        };
+        let (x_range, y_range) = door_area(direction);
         if x_range.contains(&player.body.position.0.x)
             && y_range.contains(&player.body.position.0.y)
         {
             if door.entrance {
-                if enemies.iter().any(|enemy| enemy.health != Health::Dead) {
+                if enemies.iter().any(|enemy| {
+                    enemy.health != Health::Dead
+                        && (!leave_alive || matches!(enemy.state, EnemyState::Fight(_, _)))
+                }) {
                     player.body.phrase = Some(Phrase {
                         text: "The guards are still on guard".to_owned(),
                         time: 2.,
                     });
-                } else if player.item != Item::Sword {
+                } else if !player.inventory.contains(&Item::Sword) {
                     player.body.phrase = Some(Phrase {
-                        text: "I can't leave sword here".to_owned(),
+                        text: "I need my sword to leave".to_owned(),
                         time: 2.,
                     });
                 } else {
@@ -834,14 +2238,19 @@ fn use_door(player: &mut Player, door: &mut Door, enemies: &Vec<Enemy>, assets:
                 }
                 if door.playing == 0. {
                     door.playing = 1.;
-                    play_sound_once(assets.sounds["door_locked"]);
+                    sounds.push(SoundEvent::new("door_locked", settings.sfx_volume()));
                 }
                 return false;
             }
-            if door.closed && player.item != Item::Key {
+            if door.closed && door.breakable {
+                player.body.phrase = Some(Phrase {
+                    text: "Maybe I could break it with something".to_owned(),
+                    time: 1.,
+                });
+            } else if door.closed && !player.inventory.contains(&Item::Key) {
                 if door.playing == 0. {
                     door.playing = 1.;
-                    play_sound_once(assets.sounds["door_locked"]);
+                    sounds.push(SoundEvent::new("door_locked", settings.sfx_volume()));
                 }
                 player.body.phrase = Some(Phrase {
                     text: "It's locked".to_owned(),
@@ -849,7 +2258,7 @@ fn use_door(player: &mut Player, door: &mut Door, enemies: &Vec<Enemy>, assets:
                 });
             } else {
                 if door.closed {
-                    play_sound_once(assets.sounds["door_unlock"]);
+                    sounds.push(SoundEvent::new("door_unlock", settings.sfx_volume()));
                 }
                 door.closed = false;
                 match direction {
@@ -862,139 +2271,693 @@ fn use_door(player: &mut Player, door: &mut Door, enemies: &Vec<Enemy>, assets:
                     }
                 }
                 player.body.room = to;
+                visited.insert(to.0);
             }
         }
     }
     false
 }
 
-fn swap_items(item_crate: &mut ItemCrate, player: &mut Player, assets: &Assets) -> bool {
-    if player.health == Health::Dead || item_crate.room.0 != player.body.room.0 {
-        return false;
-    }
+/// Index of the crate the player stands next to
+fn crate_in_reach(crates: &[ItemCrate], player: &Player) -> Option<usize> {
+    crates
+        .iter()
+        .position(|item_crate| in_reach(item_crate, player))
+}
+
+fn in_reach(item_crate: &ItemCrate, player: &Player) -> bool {
     let diff = item_crate.position.0 - player.body.position.0;
-    if is_key_pressed(KeyCode::E)
+    item_crate.room.0 == player.body.room.0
         && diff.length()
             <= player.body.form.direction_len(diff) + item_crate.form.direction_len(diff) + 0.02
-    {
-        (player.item, item_crate.item) = (item_crate.item.clone(), player.item.clone());
-        play_sound_once(assets.sounds["item"]);
-        true
-    } else {
-        false
+}
+
+/// Takes the dropped items the player walks over into free slots of the inventory. An
+/// item waits for the player to step off it after being dropped
+fn pick_up_items(
+    crates: &mut Vec<ItemCrate>,
+    player: &mut Player,
+    sounds: &mut Vec<SoundEvent>,
+    settings: &Settings,
+) {
+    for item_crate in crates.iter_mut() {
+        item_crate.under_player &= in_reach(item_crate, player);
+    }
+    if !settings.pickup_on_walk || player.health == Health::Dead {
+        return;
+    }
+    while player.inventory.items.len() < INVENTORY_SIZE {
+        let Some(n) = crates.iter().position(|item_crate| {
+            item_crate.dropped && !item_crate.under_player && in_reach(item_crate, player)
+        }) else {
+            return;
+        };
+        player.inventory.take(crates.remove(n).item);
+        sounds.push(SoundEvent::new("item", settings.sfx_volume()));
     }
 }
 
-pub fn update_level(level: &mut Level, screen: &Screen, assets: &Assets, dt: f32) -> bool {
-    let Level { level, backup } = level;
-    let mut next = false;
-    let player_action = player_action(screen, &mut level.player, &mut level.balls, assets, dt);
-    level
-        .enemies
-        .iter_mut()
-        .map(|enemy| {
-            (
-                enemy_action(enemy, &mut level.player, assets, dt),
-                &mut enemy.body,
-            )
-        })
-        .collect::<Vec<_>>()
-        .into_iter()
-        .chain(std::iter::once((player_action, &mut level.player.body)))
-        .for_each(|(move_action, body)| {
-            // move body
-            body.sight.0 = move_action.sight;
-            body.speed.x += 2 * move_action.move_direction.0;
-            body.speed.y += 2 * move_action.move_direction.1;
-
-            match body.speed.x.cmp(&0) {
-                std::cmp::Ordering::Less => body.speed.x += 1,
-                std::cmp::Ordering::Greater => body.speed.x -= 1,
-                _ => {}
-            }
-            body.speed.x = clamp(body.speed.x, -SPEED_STEPS, SPEED_STEPS);
-            match body.speed.y.cmp(&0) {
-                std::cmp::Ordering::Less => body.speed.y += 1,
-                std::cmp::Ordering::Greater => body.speed.y -= 1,
-                _ => {}
-            }
-            body.speed.y = clamp(body.speed.y, -SPEED_STEPS, SPEED_STEPS);
-            let speed = Vec2::new(
-                body.speed.x as f32 / SPEED_STEPS as f32,
-                body.speed.y as f32 / SPEED_STEPS as f32,
-            )
-            .clamp_length_max(1.);
-            body.position.0.x += PLAYER_MAX_SPEED * speed.x * dt;
-            body.position.0.y += PLAYER_MAX_SPEED * speed.y * dt;
-        });
-    collide(
-        level
-            .enemies
-            .iter_mut()
-            .map(|enemy| &mut enemy.body)
-            .chain(std::iter::once(&mut level.player.body))
-            .collect(),
-        &level.crates,
-    );
-    if level
-        .doors
-        .iter_mut()
-        .map(|door| {
-            door.playing = clamp(door.playing - dt, 0., door.playing);
-            door
-        })
-        .any(|door| use_door(&mut level.player, door, &level.enemies, assets))
-    {
-        next = true;
+/// Takes the item of the crate next to the player, giving the item in hand back when
+/// the inventory is full
+fn swap_items(
+    crates: &mut Vec<ItemCrate>,
+    player: &mut Player,
+    sounds: &mut Vec<SoundEvent>,
+    settings: &Settings,
+    pressed: bool,
+) -> bool {
+    if player.health == Health::Dead || !pressed {
+        return false;
     }
-    level
-        .enemies
-        .iter_mut()
-        .map(|enemy| &mut enemy.reload)
-        .chain(std::iter::once(&mut level.player.reload))
-        .for_each(|reload| {
-            reload.0 = clamp(reload.0 - dt, 0., reload.0);
-        });
-    level.balls = level
-        .balls
-        .iter_mut()
-        .map(|ball| {
-            ball.position.0 += ball.velocity.0 * dt;
-            for enemy in &mut level.enemies {
-                if ball.room != enemy.body.room || enemy.health == Health::Dead {
-                    continue;
+    let Some(n) = crate_in_reach(crates, player) else {
+        return false;
+    };
+    match player.inventory.take(crates[n].item.clone()) {
+        Some(item) => crates[n].item = item,
+        // Nothing is left in the crate
+        None => {
+            crates.remove(n);
+        }
+    }
+    sounds.push(SoundEvent::new("item", settings.sfx_volume()));
+    true
+}
+
+/// Makes the sound quieter the further from the player it happens, and not heard at all in
+/// other rooms
+fn positional_sound(
+    sounds: &mut Vec<SoundEvent>,
+    name: &'static str,
+    volume: f32,
+    (room, position): (Room, Vec2),
+    player: &Body,
+) {
+    if room != player.room {
+        return;
+    }
+    let distance = (position.distance(player.position.0) / HEARING_DISTANCE).min(1.);
+    sounds.push(SoundEvent::new(name, volume * (1. - 0.7 * distance)));
+}
+
+/// Ages the captions and adds the phrases that aren't captioned yet
+fn record_captions(captions: &mut VecDeque<Caption>, player: &Player, enemies: &[Enemy], dt: f32) {
+    captions.iter_mut().for_each(|caption| caption.time -= dt);
+    captions.retain(|caption| caption.time > 0.);
+    let speakers = std::iter::once((None, &player.body)).chain(
+        enemies
+            .iter()
+            .enumerate()
+            .map(|(n, enemy)| (Some(n), &enemy.body)),
+    );
+    for (speaker, body) in speakers {
+        let Some(phrase) = &body.phrase else {
+            continue;
+        };
+        // A phrase stays on the speaker for several frames
+        if captions
+            .iter()
+            .any(|caption| caption.speaker == speaker && caption.text == phrase.text)
+        {
+            continue;
+        }
+        if captions.len() == CAPTION_COUNT {
+            captions.pop_front();
+        }
+        captions.push_back(Caption {
+            speaker,
+            text: phrase.text.clone(),
+            time: CAPTION_TIME,
+        });
+    }
+}
+
+/// Puts the item in hand down as a new crate when there is no crate near to swap with
+fn drop_item(
+    crates: &mut Vec<ItemCrate>,
+    player: &mut Player,
+    sounds: &mut Vec<SoundEvent>,
+    settings: &Settings,
+    pressed: bool,
+) {
+    if player.health == Health::Dead || !pressed {
+        return;
+    }
+    if crate_in_reach(crates, player).is_some() {
+        return;
+    }
+    let Some(item) = player.inventory.drop() else {
+        return;
+    };
+    crates.push(ItemCrate::dropped(
+        item,
+        player.body.position.clone(),
+        player.body.room,
+        true,
+    ));
+    sounds.push(SoundEvent::new("item", settings.sfx_volume()));
+}
+
+/// Whole ticks in the time since the last frame, the rest is kept in `tick_time` for the next one
+pub fn frame_ticks(tick_time: &mut f32, dt: f32) -> u32 {
+    *tick_time += dt;
+    let ticks = ((*tick_time / TICK) as u32).min(MAX_TICKS);
+    *tick_time = (*tick_time - ticks as f32 * TICK).min(TICK);
+    ticks
+}
+
+/// Reads the input of the frame, runs the ticks of the level and plays what it sounded like
+pub fn update_level(
+    level: &mut Level,
+    screen: &Screen,
+    assets: &Assets,
+    settings: &Settings,
+    ticks: u32,
+) -> bool {
+    let view = level
+        .camera
+        .view(screen, level.level.player.body.position.0);
+    let input = LevelInput::read(&view, &level.level.player, settings);
+    let step = level.run(input, settings, ticks);
+    for sound in &step.sounds {
+        assets.play_sfx_variant(sound.name, sound.volume);
+    }
+    step.next
+}
+
+impl Level {
+    /// Runs the ticks of a frame. Presses wait for the first tick, so a frame without
+    /// ticks doesn't lose them and a frame with several doesn't repeat them
+    pub fn run(&mut self, input: LevelInput, settings: &Settings, ticks: u32) -> Step {
+        self.input.merge(input);
+        let mut result = Step::default();
+        for _ in 0..ticks {
+            let input = std::mem::take(&mut self.input);
+            self.input = input.held();
+            let step = self.update(&input, settings, TICK);
+            result.swapped |= step.swapped;
+            result.sounds.extend(step.sounds);
+            if step.next {
+                result.next = true;
+                break;
+            }
+        }
+        result
+    }
+
+    /// Steps the level with the camera, saves, restarts and the slowed down time around it
+    pub fn update(&mut self, input: &LevelInput, settings: &Settings, dt: f32) -> Step {
+        // Starts over from the config, unlike restarting from the last save
+        if input.restart_level {
+            *self = Level::load(&self.config, settings);
+            return Step::default();
+        }
+        let Level {
+            level,
+            backup,
+            outcome,
+            time,
+            camera,
+            last_stand,
+            heal_time,
+            death_fade,
+            peek,
+            path,
+            ghost,
+            show_minimap,
+            show_captions,
+            config: _,
+            input: _,
+        } = self;
+        if input.minimap {
+            *show_minimap = !*show_minimap;
+        }
+        if input.captions {
+            *show_captions = !*show_captions;
+        }
+        if camera.update(dt, input.skip) {
+            return Step::default();
+        }
+        if input.zoom_in {
+            camera.zoom_by(ZOOM_STEP);
+        }
+        if input.zoom_out {
+            camera.zoom_by(1. / ZOOM_STEP);
+        }
+        *time += dt;
+        camera.follow(level.player.body.position.0, dt);
+        *peek = if level.player.health != Health::Dead && input.peek {
+            peek_through(&level.player, &level.doors)
+        } else {
+            None
+        };
+        if level.player.health != Health::Dead {
+            if path.len() == GHOST_TICKS {
+                path.pop_front();
+            }
+            path.push_back((
+                level.player.body.room,
+                level.player.body.position.0,
+                level.player.body.sight.0,
+            ));
+        }
+        ghost.1 += 1;
+        let room = level.player.body.room;
+        if *last_stand
+            && matches!(level.player.health, Health::Low | Health::Critical)
+            && !level.last_stands.contains(&room)
+            && level
+                .enemies
+                .iter()
+                .filter(|enemy| {
+                    enemy.body.room == room && matches!(enemy.state, EnemyState::Fight(_, _))
+                })
+                .count()
+                > 1
+        {
+            level.last_stands.push(room);
+            level.slow_motion = LAST_STAND_TIME;
+            level.player.body.phrase = Some(Phrase {
+                text: "Not like this...".to_owned(),
+                time: LAST_STAND_TIME,
+            });
+        }
+        let dt = if level.slow_motion > 0. {
+            level.slow_motion -= dt;
+            dt * LAST_STAND_SCALE
+        } else {
+            dt
+        };
+        // The world slows down to a stop while the death screen fades in
+        let dt = if level.player.health == Health::Dead {
+            *death_fade += dt;
+            dt * (1. - death_progress(*death_fade, settings))
+        } else {
+            dt
+        };
+        let in_combat = level
+            .enemies
+            .iter()
+            .any(|enemy| matches!(enemy.state, EnemyState::Fight(_, _)));
+        outcome.spotted |= in_combat;
+        let heal_time = heal_time.filter(|_| !(settings.heal.out_of_combat && in_combat));
+        let step = step(level, input, settings, heal_time, dt);
+        let fighting = || {
+            level
+                .enemies
+                .iter()
+                .any(|enemy| matches!(enemy.state, EnemyState::Fight(_, _)))
+        };
+        // Save on entering a room out of a fight
+        if level.player.body.room != room && !fighting() {
+            *backup = level.clone();
+            path.clear();
+            level.player.body.phrase = Some(Phrase {
+                text: "Checkpoint".to_owned(),
+                time: 1.,
+            });
+        }
+        if step.swapped
+            && settings.save_on_swap
+            // If enemy is near don't save
+            && !level.enemies.iter().any(|enemy| {
+                let diff = enemy.body.position.0 - level.player.body.position.0;
+                let touch_distance = SLASH_LEN;
+                diff.length()
+                    < enemy.body.form.direction_len(diff)
+                        + level.player.body.form.direction_len(diff)
+                        + touch_distance
+            })
+        {
+            *backup = level.clone();
+            path.clear();
+        }
+        if level.player.health == Health::Dead
+            && death_progress(*death_fade, settings) >= 1.
+            && input.restart
+        {
+            *level = backup.clone();
+            *death_fade = 0.;
+            *ghost = (path.drain(..).collect(), 0);
+            outcome.died = true;
+        }
+        // Guards come back with a restart, but the kill still counts
+        if level
+            .enemies
+            .iter()
+            .any(|enemy| enemy.health == Health::Dead)
+        {
+            outcome.pacifist = false;
+        }
+        if step.next {
+            outcome.loot = level
+                .player
+                .inventory
+                .items
+                .iter()
+                .filter(|item| **item != Item::Sword)
+                .count() as u8;
+        }
+        step
+    }
+}
+
+/// Moves everything in the level by `dt` for the input, without touching the window or
+/// the audio. `heal_time` is `None` while the player doesn't heal
+pub fn step(
+    level: &mut LevelInner,
+    input: &LevelInput,
+    settings: &Settings,
+    heal_time: Option<f32>,
+    dt: f32,
+) -> Step {
+    let mut step = Step::default();
+    let mut sounds = Vec::new();
+    // The player stays at the door while peeking through it
+    let peeking = input.peek && peek_through(&level.player, &level.doors).is_some();
+    let mut player_input = input.player;
+    if peeking {
+        player_input.move_action.move_direction = (0, 0);
+    }
+    let balls = level.balls.len();
+    let player_action = player_action(
+        &player_input,
+        &mut level.player,
+        &mut level.balls,
+        &mut sounds,
+        settings,
+        heal_time,
+        dt,
+    );
+    let thrown = level.balls.len() > balls;
+    level.decoys.retain_mut(|decoy| {
+        decoy.time -= dt;
+        if decoy.time > 0. {
+            return true;
+        }
+        // Guards that were checking the decoy go back to their posts
+        for enemy in &mut level.enemies {
+            if matches!(enemy.state, EnemyState::LastSeen(position, _) if position == decoy.position.0)
+            {
+                enemy.state = EnemyState::Idle;
+            }
+        }
+        false
+    });
+    let surroundings = Surroundings {
+        decoys: &level.decoys,
+        crates: &level.crates,
+        doors: &level.doors,
+    };
+    let was_fighting: Vec<_> = level
+        .enemies
+        .iter()
+        .map(|enemy| matches!(enemy.state, EnemyState::Fight(_, _)))
+        .collect();
+    let mut moves: Vec<_> = level
+        .enemies
+        .iter_mut()
+        .map(|enemy| {
+            (
+                enemy_action(
+                    enemy,
+                    &mut level.player,
+                    &surroundings,
+                    &mut level.balls,
+                    &mut sounds,
+                    settings,
+                    dt,
+                ),
+                &mut enemy.body,
+                enemy.speed,
+            )
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .chain(std::iter::once((
+            player_action,
+            &mut level.player.body,
+            if level.player.sprinting {
+                SPRINT_SCALE
+            } else {
+                1.
+            },
+        )))
+        .collect();
+    for (move_action, body, max_speed) in &mut moves {
+        body.sight.0 = move_action.sight;
+        let slow = hazard_slow(&level.hazards, body);
+        move_body(body, move_action.move_direction, *max_speed * slow, dt);
+    }
+    let shouts: Vec<_> = level
+        .enemies
+        .iter()
+        .zip(was_fighting)
+        .filter_map(|(enemy, was_fighting)| match enemy.state {
+            EnemyState::Fight(target, _) if !was_fighting => {
+                Some((enemy.body.room, enemy.body.position.0, target))
+            }
+            _ => None,
+        })
+        .collect();
+    alert_allies(&mut level.enemies, &shouts);
+    // Noise is heard in the room only, doors stop it
+    let player = &level.player;
+    let speed = Vec2::new(player.body.speed.x as f32, player.body.speed.y as f32).length()
+        / SPEED_STEPS as f32;
+    let noise = if player.health == Health::Dead {
+        None
+    } else if player.sprinting || thrown {
+        Some(NOISE_RADIUS)
+    // Crouching is silent
+    } else if player.visible && speed >= 1. {
+        Some(STEP_NOISE_RADIUS)
+    } else {
+        None
+    };
+    if let Some(radius) = noise {
+        hear_noise(
+            &mut level.enemies,
+            player.body.room,
+            player.body.position.0,
+            radius,
+        );
+    }
+    let (interval, volume) = if level.player.sprinting {
+        (STEP_INTERVAL / SPRINT_SCALE, 0.7)
+    } else if level.player.visible {
+        (STEP_INTERVAL, 0.5)
+    } else {
+        (CROUCH_STEP_INTERVAL, 0.2)
+    };
+    if level.player.health != Health::Dead
+        && footstep(
+            &level.player.body,
+            &mut level.player.step_time,
+            interval,
+            dt,
+        )
+    {
+        sounds.push(SoundEvent::new("step", volume * settings.sfx_volume()));
+    }
+    // Guards are heard walking around the room, even out of view
+    for enemy in &mut level.enemies {
+        if enemy.health != Health::Dead
+            && enemy.body.room == level.player.body.room
+            && footstep(&enemy.body, &mut enemy.step_time, STEP_INTERVAL, dt)
+        {
+            positional_sound(
+                &mut sounds,
+                "step",
+                0.25 * settings.sfx_volume(),
+                (enemy.body.room, enemy.body.position.0),
+                &level.player.body,
+            );
+        }
+    }
+    collide(
+        level
+            .enemies
+            .iter_mut()
+            .map(|enemy| &mut enemy.body)
+            .chain(std::iter::once(&mut level.player.body))
+            .collect(),
+        &level.crates,
+    );
+    if hazard_damage(&level.hazards, &mut level.player, dt) {
+        sounds.push(SoundEvent::new("sword", settings.sfx_volume()));
+    }
+    if level
+        .doors
+        .iter_mut()
+        .map(|door| {
+            door.playing = clamp(door.playing - dt, 0., door.playing);
+            door
+        })
+        .any(|door| {
+            !peeking
+                && use_door(
+                    &mut level.player,
+                    door,
+                    &level.enemies,
+                    &mut level.visited,
+                    &mut sounds,
+                    settings,
+                    level.leave_alive,
+                )
+        })
+    {
+        step.next = true;
+    }
+    for enemy in &mut level.enemies {
+        if enemy.health == Health::Dead {
+            enemy.dead_time += dt;
+        }
+    }
+    level
+        .enemies
+        .iter_mut()
+        .map(|enemy| &mut enemy.reload)
+        .chain(std::iter::once(&mut level.player.reload))
+        .for_each(|reload| {
+            reload.0 = clamp(reload.0 - dt, 0., reload.0);
+        });
+    let mut deaths = Vec::new();
+    let balls: Vec<_> = level
+        .balls
+        .iter_mut()
+        .map(|ball| {
+            // The whole path of the frame is checked, so fast balls don't fly through
+            let from = ball.position.0;
+            let to = from + ball.velocity.0 * dt;
+            let wall_time = ball_wall_time(from, to, ball.radius);
+            let player = &level.player;
+            let player_time =
+                (ball.hostile && ball.room == player.body.room && player.health != Health::Dead)
+                    .then(|| ball_hit_time(from, to, ball.radius, &player.body))
+                    .flatten();
+            let enemy_hit = level
+                .enemies
+                .iter()
+                .enumerate()
+                .filter(|(_, enemy)| {
+                    !ball.hostile && ball.room == enemy.body.room && enemy.health != Health::Dead
+                })
+                .filter_map(|(n, enemy)| {
+                    ball_hit_time(from, to, ball.radius, &enemy.body).map(|time| (n, time))
+                })
+                .min_by(|(_, left), (_, right)| left.total_cmp(right));
+            let hit_time = player_time
+                .into_iter()
+                .chain(enemy_hit.map(|(_, time)| time))
+                .fold(wall_time, f32::min);
+            ball.position.0 = if hit_time < 1. {
+                // Stop at the first hit, a bit past it so a wall counts as hit
+                from + (to - from) * hit_time + (to - from).normalize_or_zero() * 1e-4
+            } else {
+                to
+            };
+            ball.velocity.0 *= BALL_DECAY.powf(dt);
+            ball.lifetime += dt;
+            if ball.lifetime > BALL_MAX_LIFETIME {
+                if let Item::Vegetable {
+                    effect: Effect::Decoy(time),
+                    ..
+                } = ball.item
+                {
+                    level.decoys.push(Decoy {
+                        item: ball.item.clone(),
+                        position: ball.position.clone(),
+                        room: ball.room,
+                        time,
+                    });
+                }
+                return None;
+            }
+            if player_time.is_some_and(|time| time <= hit_time) {
+                level.player.health.decrease();
+                return None;
+            }
+            if let Some((n, _)) = enemy_hit.filter(|(_, time)| *time <= hit_time) {
+                let enemy = &mut level.enemies[n];
+                match ball.item {
+                    Item::Vegetable {
+                        effect: Effect::Stun(time),
+                        ..
+                    } => {
+                        enemy.stun = time;
+                        return None;
+                    }
+                    Item::Vegetable {
+                        effect: Effect::Decoy(time),
+                        ..
+                    } => {
+                        level.decoys.push(Decoy {
+                            item: ball.item.clone(),
+                            position: ball.position.clone(),
+                            room: ball.room,
+                            time,
+                        });
+                        return None;
+                    }
+                    _ => {}
                 }
-                let diff = ball.position.0 - enemy.body.position.0;
-                if diff.length() < BALL_RADIUS + enemy.body.form.direction_len(diff) {
-                    enemy.health.decrease();
-                    if enemy.health == Health::Dead {
-                        let Item::Vegetable { color: (r, g, b, a), .. } = ball.item else {
-                            unreachable!()
-                        };
-                        enemy.stain = Some(Color::from_rgba(r, g, b, a));
+                enemy.health.decrease();
+                if enemy.health == Health::Dead {
+                    let Item::Vegetable {
+                        color: (r, g, b, a),
+                        ..
+                    } = ball.item
+                    else {
+                        unreachable!()
+                    };
+                    enemy.stain = Some(Color::from_rgba(r, g, b, a));
+                    let position = enemy.body.position.0;
+                    deaths.push((enemy.body.room, position, position));
+                    if let Some(item) = enemy.carries.take() {
+                        level.crates.push(ItemCrate::dropped(
+                            item,
+                            enemy.body.position.clone(),
+                            enemy.body.room,
+                            false,
+                        ));
                     }
-                    return None;
                 }
+                return None;
             }
-            if ball.position.0.x < WALL_SIZE + BALL_RADIUS
-                || ball.position.0.x > RATIO_W_H - WALL_SIZE - BALL_RADIUS
-                || ball.position.0.y < WALL_SIZE + BALL_RADIUS
-                || ball.position.0.y > 1. - WALL_SIZE - BALL_RADIUS
+            if ball.position.0.x < WALL_SIZE + ball.radius
+                || ball.position.0.x > RATIO_W_H - WALL_SIZE - ball.radius
+                || ball.position.0.y < WALL_SIZE + ball.radius
+                || ball.position.0.y > 1. - WALL_SIZE - ball.radius
             {
                 let Item::Vegetable{color: (r, g, b, a), ..} = ball.item else {
                     unreachable!()
                 };
-                let (position, direction) = if ball.position.0.x < WALL_SIZE + BALL_RADIUS {
+                if let Item::Vegetable {
+                    effect: Effect::Decoy(time),
+                    ..
+                } = ball.item
+                {
+                    level.decoys.push(Decoy {
+                        item: ball.item.clone(),
+                        position: Position(Vec2::new(
+                            clamp(ball.position.0.x, WALL_SIZE, RATIO_W_H - WALL_SIZE),
+                            clamp(ball.position.0.y, WALL_SIZE, 1. - WALL_SIZE),
+                        )),
+                        room: ball.room,
+                        time,
+                    });
+                }
+                let (position, direction) = if ball.position.0.x < WALL_SIZE + ball.radius {
                     (Position(Vec2::new(0., ball.position.0.y)), Direction::West)
-                } else if ball.position.0.x > RATIO_W_H - WALL_SIZE - BALL_RADIUS {
+                } else if ball.position.0.x > RATIO_W_H - WALL_SIZE - ball.radius {
                     (
                         Position(Vec2::new(RATIO_W_H - 4. * BALL_RADIUS, ball.position.0.y)),
                         Direction::East,
                     )
-                } else if ball.position.0.y < WALL_SIZE + BALL_RADIUS {
+                } else if ball.position.0.y < WALL_SIZE + ball.radius {
                     (Position(Vec2::new(ball.position.0.x, 0.)), Direction::North)
                 } else
-                /* ball.position.0.y > 1. - WALL_SIZE - BALL_RADIUS*/
+                /* ball.position.0.y > 1. - WALL_SIZE - ball.radius*/
                 {
                     (
                         Position(Vec2::new(ball.position.0.x, 1. - 4. * BALL_RADIUS)),
@@ -1007,19 +2970,50 @@ pub fn update_level(level: &mut Level, screen: &Screen, assets: &Assets, dt: f32
                     room: ball.room,
                     direction,
                 });
+                for door in &mut level.doors {
+                    let (x_range, y_range) = door_area(direction);
+                    if door.breakable
+                        && door.closed
+                        && door.door_from(&ball.room).map(|(to, _)| to) == Some(direction)
+                        && x_range.contains(&ball.position.0.x)
+                        && y_range.contains(&ball.position.0.y)
+                    {
+                        door.closed = false;
+                        positional_sound(
+                            &mut sounds,
+                            "glass",
+                            settings.sfx_volume(),
+                            (ball.room, ball.position.0),
+                            &level.player.body,
+                        );
+                    }
+                }
                 return None;
             }
 
             Some(ball.clone())
         })
-        .filter_map(|ball| {
-            if ball.is_none() {
-                play_sound_once(assets.sounds["splat"]);
-            }
-            ball
-        })
         .collect();
+    // Balls that are gone splat where they stopped
+    for (ball, _) in level
+        .balls
+        .iter()
+        .zip(&balls)
+        .filter(|(_, next)| next.is_none())
+    {
+        positional_sound(
+            &mut sounds,
+            "splat",
+            settings.sfx_volume(),
+            (ball.room, ball.position.0),
+            &level.player.body,
+        );
+    }
+    level.balls = balls.into_iter().flatten().collect();
+    // Falling guards are heard like shouts
+    alert_allies(&mut level.enemies, &deaths);
 
+    record_captions(&mut level.captions, &level.player, &level.enemies, dt);
     level
         .enemies
         .iter_mut()
@@ -1037,29 +3031,67 @@ pub fn update_level(level: &mut Level, screen: &Screen, assets: &Assets, dt: f32
             }
         });
 
-    if level
-        .crates
-        .iter_mut()
-        .any(|item_crate| swap_items(item_crate, &mut level.player, assets))
-        // If enemy is near don't save
-        && !level.enemies.iter().any(|enemy| {
-            let diff = enemy.body.position.0 - level.player.body.position.0;
-            let touch_distance = SLASH_LEN;
-            diff.length()
-                < enemy.body.form.direction_len(diff)
-                    + level.player.body.form.direction_len(diff)
-                    + touch_distance
-        })
-    {
-        *backup = level.clone();
+    drop_item(
+        &mut level.crates,
+        &mut level.player,
+        &mut sounds,
+        settings,
+        input.drop,
+    );
+    pick_up_items(&mut level.crates, &mut level.player, &mut sounds, settings);
+    step.swapped = swap_items(
+        &mut level.crates,
+        &mut level.player,
+        &mut sounds,
+        settings,
+        input.interact,
+    );
+    step.sounds = sounds;
+    step
+}
+
+/// Fan of triangles over what the body sees, cut by the walls of the room
+fn draw_vision_cone(screen: &Screen, body: &Body, fov_deg: f32, range: f32, color: Color) {
+    const RAYS: usize = 16;
+    let from = body.position.0;
+    let Some(sight) = body.sight.0.try_normalize() else {
+        return;
+    };
+    let half = fov_deg.to_radians() / 2.;
+    let ray_end = |n: usize| {
+        let direction = Vec2::from_angle(-half + 2. * half * n as f32 / RAYS as f32).rotate(sight);
+        // Distance to the wall the ray hits first
+        let to_wall = |position: f32, direction: f32, max: f32| {
+            if direction > f32::EPSILON {
+                (max - WALL_SIZE - position) / direction
+            } else if direction < -f32::EPSILON {
+                (WALL_SIZE - position) / direction
+            } else {
+                f32::INFINITY
+            }
+        };
+        let length = range
+            .min(to_wall(from.x, direction.x, RATIO_W_H))
+            .min(to_wall(from.y, direction.y, 1.))
+            .max(0.);
+        screen.to_px(from + direction * length)
+    };
+    let center = screen.to_px(from);
+    for n in 0..RAYS {
+        draw_triangle(center, ray_end(n), ray_end(n + 1), color);
     }
-    if level.player.health == Health::Dead && is_key_pressed(KeyCode::R) {
-        *level = backup.clone();
+}
+
+/// Part of the death fade that passed, from 0 to 1
+fn death_progress(death_fade: f32, settings: &Settings) -> f32 {
+    if settings.death_fade > 0. {
+        clamp(death_fade / settings.death_fade, 0., 1.)
+    } else {
+        1.
     }
-    next
 }
 
-fn draw_doors(screen: &Screen, player: &Player, doors: &Vec<Door>, assets: &Assets) {
+fn draw_doors(screen: &Screen, room: Room, doors: &Vec<Door>, assets: &Assets) {
     draw_texture_ex(
         assets.images["level_back"],
         screen.x,
@@ -1071,7 +3103,7 @@ fn draw_doors(screen: &Screen, player: &Player, doors: &Vec<Door>, assets: &Asse
         },
     );
     for door in doors {
-        if let Some((direction, _)) = door.door_from(&player.body.room) {
+        if let Some((direction, _)) = door.door_from(&room) {
             let rect_x = if door.entrance {
                 42.
             } else if door.closed {
@@ -1079,6 +3111,11 @@ fn draw_doors(screen: &Screen, player: &Player, doors: &Vec<Door>, assets: &Asse
             } else {
                 0.
             };
+            let color = match (door.breakable, door.closed) {
+                (true, true) => SKYBLUE,
+                (true, false) => LIGHTGRAY,
+                _ => WHITE,
+            };
 
             let (x, y, rotation_multiplier) = match direction {
                 Direction::North => (RATIO_W_H / 2., WALL_SIZE / 2. - 0.15, 1.),
@@ -1090,7 +3127,7 @@ fn draw_doors(screen: &Screen, player: &Player, doors: &Vec<Door>, assets: &Asse
                 assets.images["doors"],
                 x * screen.height + screen.x,
                 y * screen.height + screen.y,
-                WHITE,
+                color,
                 DrawTextureParams {
                     dest_size: Some(Vec2::new(WALL_SIZE * screen.height, 0.3 * screen.height)),
                     source: Some(Rect {
@@ -1183,35 +3220,278 @@ fn draw_player(player: &Player, assets: &Assets, screen: &Screen) {
     }
 }
 
-pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
-    let Level { level, .. } = level;
-    draw_doors(screen, &level.player, &level.doors, assets);
+/// Hint over a crate in reach
+fn use_hint(keys: &KeyBindings) -> String {
+    format!("{} to use", key_name(keys.interact))
+}
+
+fn skip_hint(keys: &KeyBindings) -> String {
+    let names: Vec<_> = keys.skip.iter().map(|key| key_name(*key)).collect();
+    format!("{} to skip", names.join("/"))
+}
 
-    draw_player(&level.player, assets, screen);
-    // Balls
-    for ball in &level.balls {
-        if ball.room != level.player.body.room {
+fn death_hint(keys: &KeyBindings) -> String {
+    format!("You're dead. Press {} to continue", key_name(keys.restart))
+}
+
+/// Texture draw collected before it is made, so draws can be put in order
+struct Sprite {
+    texture: &'static str,
+    x: f32,
+    y: f32,
+    color: Color,
+    params: DrawTextureParams,
+}
+
+/// Bodies with their stains, then the carried items of the guards in the room. Draws of
+/// one texture follow each other, so each texture is a single batch for any number of
+/// guards instead of breaking the batch for every guard
+fn enemy_sprites(
+    enemies: &[Enemy],
+    room: Room,
+    settings: &Settings,
+    screen: &Screen,
+) -> Vec<Sprite> {
+    let mut bodies = Vec::new();
+    let mut items = Vec::new();
+    for enemy in enemies {
+        let corpse_alpha = if settings.fade_corpses && enemy.health == Health::Dead {
+            clamp(
+                1. - (enemy.dead_time - CORPSE_TIME) / CORPSE_FADE_TIME,
+                0.,
+                1.,
+            )
+        } else {
+            1.
+        };
+        if enemy.body.room != room || corpse_alpha == 0. {
             continue;
         }
-        draw_texture_ex(
-            assets.images["items"],
-            (ball.position.0.x - BALL_RADIUS) * screen.height + screen.x,
-            (ball.position.0.y - BALL_RADIUS) * screen.height + screen.y,
-            WHITE,
-            DrawTextureParams {
+        let calm = enemy.health != Health::Dead
+            && matches!(enemy.state, EnemyState::Idle)
+            && enemy.alertness == 0.;
+        let color = if settings.fade_idle_enemies && calm {
+            Color { a: 0.6, ..WHITE }
+        } else {
+            WHITE
+        };
+        // Wounded guards are reddened like the player's blood overlay
+        let color = if enemy.tough && enemy.health == Health::Critical {
+            Color {
+                g: 0.6,
+                b: 0.6,
+                ..color
+            }
+        } else {
+            Color {
+                a: color.a * corpse_alpha,
+                ..color
+            }
+        };
+        bodies.push(Sprite {
+            texture: "enemy",
+            x: (enemy.body.position.0.x - enemy.body.form.x_r()) * screen.height + screen.x,
+            y: (enemy.body.position.0.y - enemy.body.form.y_r()) * screen.height + screen.y,
+            color,
+            params: DrawTextureParams {
                 dest_size: Some(Vec2 {
-                    x: 2. * BALL_RADIUS * screen.height,
-                    y: 2. * BALL_RADIUS * screen.height,
+                    x: 2. * enemy.body.form.x_r() * screen.height,
+                    y: 2. * enemy.body.form.y_r() * screen.height,
                 }),
-                source: Some(ball.item.rect()),
+                source: Some(if enemy.health == Health::Dead {
+                    Rect {
+                        x: 250.,
+                        y: 10.,
+                        w: 170.,
+                        h: 90.,
+                    }
+                } else if enemy.reload.0 < 0.2 {
+                    Rect {
+                        x: 10.,
+                        y: 10.,
+                        w: 100.,
+                        h: 170.,
+                    }
+                } else {
+                    Rect {
+                        x: 120.,
+                        y: 10.,
+                        w: 115.,
+                        h: 170.,
+                    }
+                }),
+                flip_x: enemy.body.sight.0.x < 0.,
                 ..Default::default()
             },
-        );
-    }
+        });
+        if let Some(color) = enemy.stain {
+            let color = Color {
+                a: color.a * corpse_alpha,
+                ..color
+            };
+            bodies.push(Sprite {
+                texture: "enemy",
+                x: (enemy.body.position.0.x - enemy.body.form.x_r() / 3.) * screen.height
+                    + screen.x,
+                y: (enemy.body.position.0.y - enemy.body.form.y_r()) * screen.height + screen.y,
+                color,
+                params: DrawTextureParams {
+                    source: Some(Rect {
+                        x: 10.,
+                        y: 190.,
+                        w: 50.,
+                        h: 40.,
+                    }),
+                    dest_size: Some(Vec2::new(
+                        enemy.body.form.x_r() * 0.6 * screen.height,
+                        enemy.body.form.y_r() * screen.height,
+                    )),
+                    ..Default::default()
+                },
+            });
+        }
+        // Carried item hangs on the belt
+        if let Some(item) = &enemy.carries {
+            items.push(Sprite {
+                texture: "items",
+                x: (enemy.body.position.0.x - BALL_RADIUS) * screen.height + screen.x,
+                y: enemy.body.position.0.y * screen.height + screen.y,
+                color,
+                params: DrawTextureParams {
+                    dest_size: Some(Vec2::new(
+                        2. * BALL_RADIUS * screen.height,
+                        2. * BALL_RADIUS * screen.height,
+                    )),
+                    source: Some(item.rect()),
+                    ..Default::default()
+                },
+            });
+        }
+    }
+    bodies.extend(items);
+    bodies
+}
+
+pub fn draw_level(level: &Level, assets: &Assets, settings: &Settings, screen: &Screen) {
+    let Level {
+        level,
+        camera,
+        death_fade,
+        peek,
+        ghost,
+        show_minimap,
+        show_captions,
+        ..
+    } = level;
+    let hud = screen;
+    let screen = &camera.view(hud, level.player.body.position.0);
+    // While peeking the room behind the door is drawn instead
+    let room = peek.map_or(level.player.body.room, |(_, to)| to);
+    draw_doors(screen, room, &level.doors, assets);
+
+    // Hazards
+    for hazard in &level.hazards {
+        if hazard.room != room {
+            continue;
+        }
+        let color = match hazard.kind {
+            HazardKind::Damage { .. } => Color::from_rgba(255, 80, 0, 96),
+            HazardKind::Slow { .. } => Color::from_rgba(60, 120, 255, 96),
+        };
+        draw_rect(
+            screen,
+            hazard.rect.x,
+            hazard.rect.y,
+            hazard.rect.w,
+            hazard.rect.h,
+            color,
+        );
+    }
+
+    // Ghost of the previous life
+    if let Some((ghost_room, position, sight)) = ghost.0.get(ghost.1) {
+        if settings.show_ghost && *ghost_room == room {
+            let form = level.player.body.form;
+            draw_texture_ex(
+                assets.images["player"],
+                (position.x - form.x_r()) * screen.height + screen.x,
+                (position.y - form.y_r()) * screen.height + screen.y,
+                Color { a: 0.3, ..WHITE },
+                DrawTextureParams {
+                    dest_size: Some(Vec2 {
+                        x: 2. * form.x_r() * screen.height,
+                        y: 2. * form.y_r() * screen.height,
+                    }),
+                    source: Some(Rect {
+                        x: 10.,
+                        y: 10.,
+                        w: 100.,
+                        h: 150.,
+                    }),
+                    flip_x: sight.x < 0.,
+                    ..Default::default()
+                },
+            );
+        }
+    }
+    if peek.is_none() {
+        draw_player(&level.player, assets, screen);
+        let player = &level.player;
+        if player.stamina < 1. && player.health != Health::Dead {
+            let x = player.body.position.0.x - player.body.form.x_r();
+            let y = player.body.position.0.y + player.body.form.y_r() + 0.005;
+            let width = 2. * player.body.form.x_r();
+            let color = if player.exhausted { GRAY } else { SKYBLUE };
+            draw_rect(screen, x, y, width, 0.006, Color::from_rgba(0, 0, 0, 128));
+            draw_rect(screen, x, y, width * player.stamina, 0.006, color);
+        }
+    }
+    // Balls
+    for ball in &level.balls {
+        if ball.room != room {
+            continue;
+        }
+        draw_texture_ex(
+            assets.images["items"],
+            (ball.position.0.x - ball.radius) * screen.height + screen.x,
+            (ball.position.0.y - ball.radius) * screen.height + screen.y,
+            WHITE,
+            DrawTextureParams {
+                dest_size: Some(Vec2 {
+                    x: 2. * ball.radius * screen.height,
+                    y: 2. * ball.radius * screen.height,
+                }),
+                source: Some(ball.item.rect()),
+                ..Default::default()
+            },
+        );
+    }
+
+    // Decoys
+    for decoy in &level.decoys {
+        if decoy.room != room {
+            continue;
+        }
+        let radius = decoy.item.ball_radius();
+        draw_texture_ex(
+            assets.images["items"],
+            (decoy.position.0.x - radius) * screen.height + screen.x,
+            (decoy.position.0.y - radius) * screen.height + screen.y,
+            WHITE,
+            DrawTextureParams {
+                dest_size: Some(Vec2 {
+                    x: 2. * radius * screen.height,
+                    y: 2. * radius * screen.height,
+                }),
+                source: Some(decoy.item.rect()),
+                ..Default::default()
+            },
+        );
+    }
 
     // Stains
     for stain in &level.stains {
-        if stain.room != level.player.body.room {
+        if stain.room != room {
             continue;
         }
         let rotation = match stain.direction {
@@ -1242,73 +3522,103 @@ pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
         );
     }
 
+    // Vision cones
+    if settings.show_vision_cones {
+        let range = settings.difficulty.params().detection * settings.enemy_view_range;
+        for enemy in &level.enemies {
+            if enemy.body.room != room || enemy.health == Health::Dead {
+                continue;
+            }
+            let color = if matches!(enemy.state, EnemyState::Fight(_, _)) {
+                Color::from_rgba(255, 0, 0, 48)
+            } else {
+                Color::from_rgba(255, 255, 0, 48)
+            };
+            draw_vision_cone(screen, &enemy.body, settings.enemy_fov, range, color);
+        }
+    }
+
+    // Last seen positions
+    if settings.show_last_seen {
+        for enemy in &level.enemies {
+            if enemy.body.room != room {
+                continue;
+            }
+            if let EnemyState::LastSeen(position, _) = enemy.state {
+                draw_circ(
+                    screen,
+                    position.x,
+                    position.y,
+                    PLAYER_RADIUS,
+                    Color::from_rgba(255, 255, 255, 64),
+                );
+            }
+        }
+    }
+
     // Enemies
+    for sprite in enemy_sprites(&level.enemies, room, settings, screen) {
+        draw_texture_ex(
+            assets.images[sprite.texture],
+            sprite.x,
+            sprite.y,
+            sprite.color,
+            sprite.params,
+        );
+    }
+    // Health bars are shapes, drawn after all the sprites of the guards
     for enemy in &level.enemies {
-        if enemy.body.room != level.player.body.room {
+        if enemy.body.room != room {
             continue;
         }
-        draw_texture_ex(
-            assets.images["enemy"],
-            (enemy.body.position.0.x - enemy.body.form.x_r()) * screen.height + screen.x,
-            (enemy.body.position.0.y - enemy.body.form.y_r()) * screen.height + screen.y,
-            WHITE,
-            DrawTextureParams {
-                dest_size: Some(Vec2 {
-                    x: 2. * enemy.body.form.x_r() * screen.height,
-                    y: 2. * enemy.body.form.y_r() * screen.height,
-                }),
-                source: Some(if enemy.health == Health::Dead {
-                    Rect {
-                        x: 250.,
-                        y: 10.,
-                        w: 170.,
-                        h: 90.,
-                    }
-                } else if enemy.reload.0 < 0.2 {
-                    Rect {
-                        x: 10.,
-                        y: 10.,
-                        w: 100.,
-                        h: 170.,
-                    }
-                } else {
-                    Rect {
-                        x: 120.,
-                        y: 10.,
-                        w: 115.,
-                        h: 170.,
-                    }
-                }),
-                flip_x: enemy.body.sight.0.x < 0.,
-                ..Default::default()
-            },
-        );
-        if let Some(color) = enemy.stain {
-            draw_texture_ex(
-                assets.images["enemy"],
-                (enemy.body.position.0.x - enemy.body.form.x_r() / 3.) * screen.height + screen.x,
-                (enemy.body.position.0.y - enemy.body.form.y_r()) * screen.height + screen.y,
-                color,
-                DrawTextureParams {
-                    source: Some(Rect {
-                        x: 10.,
-                        y: 190.,
-                        w: 50.,
-                        h: 40.,
-                    }),
-                    dest_size: Some(Vec2::new(
-                        enemy.body.form.x_r() * 0.6 * screen.height,
-                        enemy.body.form.y_r() * screen.height,
-                    )),
-                    ..Default::default()
-                },
-            );
+        if enemy.tough && enemy.health != Health::Dead {
+            let part = if enemy.health == Health::Low { 1. } else { 0.5 };
+            let x = enemy.body.position.0.x - enemy.body.form.x_r();
+            let y = enemy.body.position.0.y + enemy.body.form.y_r() + 0.005;
+            let width = 2. * enemy.body.form.x_r();
+            draw_rect(screen, x, y, width, 0.006, Color::from_rgba(0, 0, 0, 128));
+            draw_rect(screen, x, y, width * part, 0.006, RED);
+        }
+    }
+
+    // Alerts
+    for enemy in &level.enemies {
+        if enemy.body.room != room {
+            continue;
+        }
+        if enemy.health != Health::Dead {
+            let x = enemy.body.position.0.x - 0.01;
+            let y = enemy.body.position.0.y - enemy.body.form.y_r() - 0.02;
+            if enemy.stun > 0. {
+                draw_txt(screen, "*", x, y, 0.06, SKYBLUE);
+                continue;
+            }
+            match enemy.state {
+                EnemyState::Fight(_, _) => draw_txt(screen, "!", x, y, 0.06, RED),
+                EnemyState::LastSeen(_, _) => draw_txt(screen, "?", x, y, 0.06, ORANGE),
+                EnemyState::Suspicious(_) | EnemyState::Idle if enemy.alertness > 0. => {
+                    let color = Color {
+                        a: 0.3 + 0.7 * enemy.alertness,
+                        ..YELLOW
+                    };
+                    draw_txt(screen, "?", x, y, 0.06, color);
+                    draw_rect(
+                        screen,
+                        enemy.body.position.0.x - enemy.body.form.x_r(),
+                        y + 0.01,
+                        2. * enemy.body.form.x_r() * enemy.alertness,
+                        0.006,
+                        color,
+                    );
+                }
+                _ => {}
+            }
         }
     }
 
-    // Crates
+    // Crates, in several passes so draws of the same texture follow each other and get batched
     for item_crate in &level.crates {
-        if item_crate.room != level.player.body.room {
+        if item_crate.room != room {
             continue;
         }
         draw_texture_ex(
@@ -1324,6 +3634,11 @@ pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
                 ..Default::default()
             },
         );
+    }
+    for item_crate in &level.crates {
+        if item_crate.room != room {
+            continue;
+        }
         draw_texture_ex(
             assets.images["items"],
             (item_crate.position.0.x - 1.5 * BALL_RADIUS) * screen.height + screen.x,
@@ -1338,6 +3653,11 @@ pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
                 ..Default::default()
             },
         );
+    }
+    for item_crate in &level.crates {
+        if item_crate.room != room {
+            continue;
+        }
         let diff = item_crate.position.0 - level.player.body.position.0;
         if diff.length()
             <= level.player.body.form.direction_len(diff)
@@ -1346,7 +3666,7 @@ pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
         {
             draw_txt(
                 &screen,
-                "E to use",
+                &use_hint(&settings.keys),
                 item_crate.position.0.x,
                 item_crate.position.0.y - item_crate.form.y_r() - 0.02,
                 0.08,
@@ -1362,7 +3682,7 @@ pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
         .map(|enemy| &enemy.body)
         .chain(std::iter::once(&level.player.body))
     {
-        if body.room != level.player.body.room {
+        if body.room != room {
             continue;
         }
         let Some(phrase) = &body.phrase else {
@@ -1401,26 +3721,1625 @@ pub fn draw_level(level: &Level, assets: &Assets, screen: &Screen) {
         }
     }
 
-    if level.player.health == Health::Low {
+    // Only the part next to the door can be seen from the doorway
+    if let Some((direction, _)) = peek {
+        let (x, y, w, h) = match direction.inverse() {
+            Direction::North => (0., PEEK_DEPTH, RATIO_W_H, 1. - PEEK_DEPTH),
+            Direction::South => (0., 0., RATIO_W_H, 1. - PEEK_DEPTH),
+            Direction::East => (0., 0., RATIO_W_H - PEEK_DEPTH, 1.),
+            Direction::West => (PEEK_DEPTH, 0., RATIO_W_H - PEEK_DEPTH, 1.),
+        };
+        draw_rect(screen, x, y, w, h, BLACK);
+    }
+
+    draw_letterbox(hud);
+    if level.slow_motion > 0. {
+        draw_rect(hud, 0., 0., RATIO_W_H, 1., Color::from_rgba(0, 0, 0, 64));
+    }
+    let safe = &safe_area(hud, settings.hud_inset);
+    if *show_minimap {
+        let cleared = level
+            .visited
+            .iter()
+            .copied()
+            .filter(|&room| {
+                !level
+                    .enemies
+                    .iter()
+                    .any(|enemy| enemy.body.room.0 == room && enemy.health != Health::Dead)
+            })
+            .collect();
+        draw_minimap(
+            safe,
+            &level.doors,
+            level.player.body.room,
+            &level.visited,
+            &cleared,
+        );
+    }
+    draw_inventory(safe, assets, &level.player.inventory);
+    if *show_captions {
+        draw_captions(safe, &level.captions);
+    }
+    if matches!(camera.state, CameraState::Intro { .. }) {
+        draw_centered_txt(safe, &skip_hint(&settings.keys), 0.95, 0.05, WHITE);
+    }
+    if matches!(level.player.health, Health::Low | Health::Critical) {
         draw_texture_ex(
             assets.images["blood"],
-            screen.x,
-            screen.y,
+            hud.x,
+            hud.y,
             WHITE,
             DrawTextureParams {
-                dest_size: Some(Vec2::new(screen.width, screen.height)),
+                dest_size: Some(Vec2::new(hud.width, hud.height)),
                 ..Default::default()
             },
         );
+        // Bleeding reddens the screen more and more
+        if level.player.health == Health::Critical {
+            let progress = level.player.bleed_time / BLEED_TIME;
+            draw_rect(
+                hud,
+                0.,
+                0.,
+                RATIO_W_H,
+                1.,
+                Color::from_rgba(128, 0, 0, (32. + 64. * progress) as u8),
+            );
+        }
     } else if level.player.health == Health::Dead {
+        let progress = death_progress(*death_fade, settings);
         draw_rect(
-            &screen,
+            hud,
             0.,
             0.,
             RATIO_W_H,
             1.,
-            Color::from_rgba(128, 0, 0, 128),
+            Color::from_rgba(128, 0, 0, (128. * progress) as u8),
+        );
+        if progress >= 1. {
+            draw_centered_txt(safe, &death_hint(&settings.keys), 0.5, 0.1, WHITE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The random generator is global, tests drawing from it take turns
+    static RNG: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn config(yaml: &str) -> LevelConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn load(yaml: &str, settings: &Settings) -> Level {
+        let _rng = RNG.lock().unwrap_or_else(|err| err.into_inner());
+        Level::load(&config(yaml), settings)
+    }
+
+    fn body(x: f32, y: f32) -> Body {
+        Body {
+            position: Position(Vec2::new(x, y)),
+            form: Form::Rect {
+                width: 1.5 * PLAYER_RADIUS,
+                height: 1.5 * PLAYER_RADIUS,
+            },
+            sight: Sight(Vec2::new(1., 0.)),
+            speed: Speed::default(),
+            room: Room(0),
+            phrase: None,
+        }
+    }
+
+    fn player(x: f32, y: f32) -> Player {
+        Player {
+            body: body(x, y),
+            reload: Reload::default(),
+            health: Health::Full,
+            inventory: Inventory::new(Item::Sword),
+            visible: true,
+            heal_time: 0.,
+            bleed_time: 0.,
+            hazard_time: 0.,
+            step_time: 0.,
+            stamina: 1.,
+            exhausted: false,
+            sprinting: false,
+        }
+    }
+
+    fn hazard(kind: HazardKind) -> Hazard {
+        Hazard {
+            rect: Rect::new(0.5, 0.25, 0.5, 0.5),
+            kind,
+            room: Room(0),
+        }
+    }
+
+    #[test]
+    fn health_decreases_step_by_step() {
+        let mut health = Health::Full;
+        for expected in [Health::Low, Health::Critical, Health::Dead, Health::Dead] {
+            health.decrease();
+            assert_eq!(health, expected);
+        }
+    }
+
+    #[test]
+    fn bandage_heals_a_tier() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        let player = &mut level.level.player;
+        player.visible = true;
+        player.health = Health::Critical;
+        // Taken from a crate of the room
+        let position = player.body.position.clone();
+        level.level.crates = vec![ItemCrate::new(Item::Bandage, position, Room(0))];
+        let interact = LevelInput {
+            interact: true,
+            ..walk((0, 0))
+        };
+        level.update(&interact, &settings, TICK);
+        assert!(level.level.player.inventory.items == [Item::Sword, Item::Bandage]);
+        let mut attack = walk((0, 0));
+        attack.player.attack = true;
+        level.update(&attack, &settings, TICK);
+        let player = &mut level.level.player;
+        assert_eq!(player.health, Health::Low);
+        // Used up
+        assert!(player.inventory.items == [Item::Sword]);
+        player.inventory.take(Item::Bandage);
+        level.update(&attack, &settings, TICK);
+        let player = &mut level.level.player;
+        assert_eq!(player.health, Health::Full);
+        // Nothing to heal keeps it
+        player.inventory.take(Item::Bandage);
+        level.update(&attack, &settings, TICK);
+        let player = &level.level.player;
+        assert_eq!(player.health, Health::Full);
+        assert!(player.inventory.items == [Item::Sword, Item::Bandage]);
+    }
+
+    #[test]
+    fn damage_hazard_hurts_on_its_interval() {
+        let hazards = [hazard(HazardKind::Damage { interval: 1. })];
+        let mut player = player(0.75, 0.5);
+        let hurts = (0..25)
+            .filter(|_| hazard_damage(&hazards, &mut player, 0.1))
+            .count();
+        // Hurt at 1 and 2 seconds, not yet at 2.5
+        assert_eq!(hurts, 2);
+        assert_eq!(player.health, Health::Critical);
+        // Stepping out resets the timer
+        player.body.position.0.x = 0.2;
+        assert!(!hazard_damage(&hazards, &mut player, 0.1));
+        assert_eq!(player.hazard_time, 0.);
+    }
+
+    #[test]
+    fn slow_hazard_reduces_speed() {
+        let hazards = [hazard(HazardKind::Slow { factor: 0.5 })];
+        let mut outside = body(0.1, 0.5);
+        let mut inside = body(0.6, 0.5);
+        for _ in 0..30 {
+            for body in [&mut outside, &mut inside] {
+                let slow = hazard_slow(&hazards, body);
+                move_body(body, (1, 0), slow, TICK);
+            }
+        }
+        let outside = outside.position.0.x - 0.1;
+        let inside = inside.position.0.x - 0.6;
+        assert!(outside > 0.);
+        assert!((inside - 0.5 * outside).abs() < 1e-5);
+        assert_eq!(hazard_slow(&hazards, &body(0.1, 0.5)), 1.);
+    }
+
+    fn crates(positions: &[(f32, f32)]) -> Vec<ItemCrate> {
+        positions
+            .iter()
+            .map(|&(x, y)| ItemCrate::new(Item::Key, Position(Vec2::new(x, y)), Room(0)))
+            .collect()
+    }
+
+    fn body_form() -> Form {
+        body(0., 0.).form
+    }
+
+    fn in_crate(point: Vec2, crates: &[ItemCrate]) -> bool {
+        crates.iter().any(|item_crate| {
+            let diff = point - item_crate.position.0;
+            diff.length() < body_form().direction_len(diff) + item_crate.form.direction_len(diff)
+        })
+    }
+
+    #[test]
+    fn path_goes_around_crates() {
+        // A column of crates between the guard and the target with a gap at the bottom
+        let column: Vec<_> = (0..11).map(|n| (0.8, 0.05 + 0.07 * n as f32)).collect();
+        let crates = crates(&column);
+        let (from, to) = (Vec2::new(0.4, 0.4), Vec2::new(1.2, 0.4));
+        let path = find_path(from, to, body_form(), &crates, Room(0)).unwrap();
+        assert!(path.iter().all(|waypoint| !in_crate(*waypoint, &crates)));
+        assert!(path.iter().any(|waypoint| waypoint.y > 0.75));
+        assert_eq!(*path.last().unwrap(), cell_center(cell_of(to)));
+        let mut previous = cell_of(from);
+        for waypoint in &path {
+            let cell = cell_of(*waypoint);
+            assert!((cell.0 - previous.0).abs() <= 1 && (cell.1 - previous.1).abs() <= 1);
+            previous = cell;
+        }
+        // Crates of another room are not in the way
+        let straight = find_path(from, to, body_form(), &crates, Room(1)).unwrap();
+        assert!(straight.len() < path.len());
+        assert!(straight
+            .iter()
+            .all(|waypoint| (waypoint.y - 0.4).abs() < GRID_CELL));
+    }
+
+    #[test]
+    fn path_to_a_blocked_goal_ends_next_to_it() {
+        let crates = crates(&[(1.2, 0.4)]);
+        let to = Vec2::new(1.2, 0.4);
+        let path = find_path(Vec2::new(0.4, 0.4), to, body_form(), &crates, Room(0)).unwrap();
+        let end = *path.last().unwrap();
+        assert!(!in_crate(end, &crates));
+        assert!(end.distance(to) < 2. * GRID_CELL);
+    }
+
+    #[test]
+    fn path_to_the_own_cell_is_empty() {
+        let from = Vec2::new(0.4, 0.4);
+        let path = find_path(from, from + 0.001, body_form(), &[], Room(0));
+        assert_eq!(path, Some(Vec::new()));
+    }
+
+    #[test]
+    fn path_is_none_when_walled_in() {
+        let ring: Vec<_> = (0..12)
+            .map(|n| {
+                let angle = n as f32 * std::f32::consts::TAU / 12.;
+                (0.4 + 0.1 * angle.cos(), 0.4 + 0.1 * angle.sin())
+            })
+            .collect();
+        let crates = crates(&ring);
+        let path = find_path(
+            Vec2::new(0.4, 0.4),
+            Vec2::new(1.2, 0.4),
+            body_form(),
+            &crates,
+            Room(0),
         );
-        draw_centered_txt(&screen, "You're dead. Press R to continue", 0.5, 0.1, WHITE);
+        assert!(path.is_none());
+    }
+
+    #[test]
+    fn in_view_sees_ahead_within_range() {
+        // Facing east
+        let guard = body(0.5, 0.5);
+        assert!(in_view(&guard, Vec2::new(0.9, 0.55), 90., 0.5, &[]));
+        // Outside the cone
+        assert!(!in_view(&guard, Vec2::new(0.1, 0.5), 90., 0.5, &[]));
+        assert!(!in_view(&guard, Vec2::new(0.5, 0.9), 90., 0.5, &[]));
+        assert!(in_view(&guard, Vec2::new(0.5, 0.9), 360., 0.5, &[]));
+        // Beyond the range
+        assert!(!in_view(&guard, Vec2::new(1.1, 0.5), 90., 0.5, &[]));
+    }
+
+    #[test]
+    fn in_view_is_blocked_by_crates_of_the_room() {
+        let guard = body(0.5, 0.5);
+        let target = Vec2::new(0.9, 0.5);
+        let crates = crates(&[(0.7, 0.5)]);
+        assert!(!in_view(&guard, target, 90., 0.5, &crates));
+        let mut elsewhere = crates.clone();
+        elsewhere[0].room = Room(1);
+        assert!(in_view(&guard, target, 90., 0.5, &elsewhere));
+    }
+
+    #[test]
+    fn closed_doors_block_sight_through_their_recess() {
+        let mut door = Door::new(Room(0), Room(1), Direction::West, true, false, false);
+        // Someone standing in the recess of the west door
+        let inside = Vec2::new(0.03, 0.5);
+        let from = Vec2::new(0.8, 0.5);
+        assert!(!can_see(from, inside, &[door.clone()], Room(0)));
+        assert!(can_see(from, Vec2::new(0.5, 0.5), &[door.clone()], Room(0)));
+        // Doors of other rooms don't matter
+        assert!(can_see(from, inside, &[door.clone()], Room(2)));
+        door.closed = false;
+        assert!(can_see(from, inside, &[door], Room(0)));
+    }
+
+    #[test]
+    fn diagonal_is_no_faster_than_straight() {
+        // Distance walked in a second at full speed
+        let walked = |direction| {
+            let mut body = body(0.5, 0.5);
+            for _ in 0..SPEED_STEPS {
+                move_body(&mut body, direction, 1., TICK);
+            }
+            let start = body.position.0;
+            for _ in 0..60 {
+                move_body(&mut body, direction, 1., TICK);
+            }
+            body.position.0.distance(start)
+        };
+        let straight = walked((1, 0));
+        assert!((straight - PLAYER_MAX_SPEED).abs() < 1e-4);
+        assert!((walked((1, 1)) - straight).abs() < 1e-4);
+        assert!((walked((-1, 1)) - straight).abs() < 1e-4);
+    }
+
+    #[test]
+    fn body_wedged_between_crates_and_the_wall_stays_in() {
+        let crates = crates(&[(0.06, 0.46), (0.06, 0.54), (0.03, 0.5)]);
+        let mut player = body(0.04, 0.5);
+        for _ in 0..60 {
+            let before = player.position.0;
+            collide(vec![&mut player], &crates);
+            let position = player.position.0;
+            assert!(position.is_finite());
+            let (x_r, y_r) = (player.form.x_r(), player.form.y_r());
+            assert!((WALL_SIZE + x_r..=RATIO_W_H - WALL_SIZE - x_r).contains(&position.x));
+            assert!((WALL_SIZE + y_r..=1. - WALL_SIZE - y_r).contains(&position.y));
+            // Pushed out bit by bit, not flung across the room
+            assert!(position.distance(before) <= MAX_COLLISION_SHIFT + 1e-6);
+        }
+    }
+
+    #[test]
+    fn direction_len_reaches_sides_and_corner() {
+        let rect = Form::Rect {
+            width: 0.5,
+            height: 0.5,
+        };
+        assert_eq!(rect.direction_len(Vec2::new(1., 0.)), 0.5);
+        assert_eq!(rect.direction_len(Vec2::new(0., -1.)), 0.5);
+        let corner = rect.direction_len(Vec2::new(1., 1.));
+        assert!((corner - 0.5 * 2f32.sqrt()).abs() < 1e-6);
+        let wide = Form::Rect {
+            width: 2.,
+            height: 1.,
+        };
+        assert_eq!(wide.direction_len(Vec2::new(-3., 0.)), 2.);
+        assert_eq!(wide.direction_len(Vec2::new(0., 3.)), 1.);
+        assert!((wide.direction_len(Vec2::new(2., 1.)) - 5f32.sqrt()).abs() < 1e-6);
+        // No direction gives the nearest side
+        assert_eq!(wide.direction_len(Vec2::ZERO), 1.);
+        let circle = Form::Circle { radius: 0.3 };
+        assert_eq!(circle.direction_len(Vec2::new(1., 1.)), 0.3);
+    }
+
+    const TWO_ROOMS: &str = "
+rooms:
+- id: 0
+  enter: North
+  enemies: 0
+  doors:
+  - direction: South
+    to: 1
+- id: 1
+  enemies: 0
+  doors: []
+";
+
+    fn tomato() -> Item {
+        Item::Vegetable {
+            name: "tomato".to_owned(),
+            idx: 0,
+            color: (212, 0, 0, 128),
+            radius: None,
+            speed: None,
+            effect: Effect::default(),
+            count: 3,
+        }
+    }
+
+    fn walk(direction: (i32, i32)) -> LevelInput {
+        LevelInput {
+            player: PlayerInput {
+                move_action: MoveAction {
+                    move_direction: direction,
+                    sight: Vec2::new(0., 1.),
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn level_steps_without_a_window() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        level.level.player.body.position.0.x = RATIO_W_H / 2.;
+        let mut sounds = Vec::new();
+        for _ in 0..300 {
+            let step = level.update(&walk((0, 1)), &settings, TICK);
+            sounds.extend(step.sounds);
+            if level.level.player.body.room == Room(1) {
+                break;
+            }
+        }
+        assert_eq!(level.level.player.body.room, Room(1));
+        assert!(level.level.visited.contains(&1));
+        // Entering a room out of a fight saves
+        assert_eq!(level.backup.player.body.room, Room(1));
+        assert!(sounds.iter().any(|sound| sound.name == "step"));
+        assert!(level.time > 0.);
+    }
+
+    #[test]
+    fn throw_is_heard_not_played() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        level.level.player.inventory = Inventory {
+            items: vec![Item::Sword, tomato()],
+            selected: 1,
+        };
+        level.level.player.visible = true;
+        let mut input = walk((0, 0));
+        input.player.attack = true;
+        let step = level.update(&input, &settings, TICK);
+        assert_eq!(level.level.balls.len(), 1);
+        assert_eq!(
+            step.sounds,
+            vec![SoundEvent::new("throw", settings.sfx_volume())]
+        );
+        // Dropping the tomato puts it into a new crate
+        let crates = level.level.crates.len();
+        let step = level.update(
+            &LevelInput {
+                drop: true,
+                ..walk((0, 0))
+            },
+            &settings,
+            TICK,
+        );
+        assert_eq!(level.level.crates.len(), crates + 1);
+        assert!(step
+            .sounds
+            .contains(&SoundEvent::new("item", settings.sfx_volume())));
+    }
+
+    #[test]
+    fn vegetables_fly_as_configured() {
+        let settings = Settings::default();
+        let potato = Item::Vegetable {
+            name: "potato".to_owned(),
+            idx: 2,
+            color: (170, 136, 0, 128),
+            radius: Some(0.014),
+            speed: Some(1.3),
+            effect: Effect::default(),
+            count: 1,
+        };
+        let mut small_tomato = tomato();
+        if let Item::Vegetable { radius, speed, .. } = &mut small_tomato {
+            *radius = Some(0.008);
+            *speed = Some(2.2);
+        }
+        for (item, radius, speed) in [
+            (potato, 0.014, 1.3),
+            (small_tomato, 0.008, 2.2),
+            (tomato(), BALL_RADIUS, BALL_SPEED),
+        ] {
+            let mut level = load(TWO_ROOMS, &settings);
+            level.level.player.inventory = Inventory {
+                items: vec![Item::Sword, item],
+                selected: 1,
+            };
+            level.level.player.visible = true;
+            let mut input = walk((0, 0));
+            input.player.attack = true;
+            level.update(&input, &settings, TICK);
+            let ball = &level.level.balls[0];
+            assert_eq!(ball.radius, radius);
+            // Slowed down for the tick it already flew
+            let speed = speed * BALL_DECAY.powf(TICK);
+            assert!((ball.velocity.0.length() - speed).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn walking_over_a_dropped_item_picks_it_up() {
+        let settings = Settings {
+            pickup_on_walk: true,
+            ..Default::default()
+        };
+        let mut level = load(TWO_ROOMS, &settings);
+        level.level.crates.clear();
+        level.level.player.inventory = Inventory {
+            items: vec![Item::Sword, tomato()],
+            selected: 1,
+        };
+        let drop = LevelInput {
+            drop: true,
+            ..walk((0, 0))
+        };
+        level.update(&drop, &settings, TICK);
+        assert_eq!(level.level.crates.len(), 1);
+        // Standing on the item just dropped doesn't take it back
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert_eq!(level.level.crates.len(), 1);
+        let dropped_at = level.level.player.body.position.0;
+        level.level.player.body.position.0.x += 0.5;
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert_eq!(level.level.crates.len(), 1);
+        level.level.player.body.position.0 = dropped_at;
+        let step = level.update(&walk((0, 0)), &settings, TICK);
+        assert!(level.level.crates.is_empty());
+        assert!(level.level.player.inventory.items == [Item::Sword, tomato()]);
+        assert!(step
+            .sounds
+            .contains(&SoundEvent::new("item", settings.sfx_volume())));
+    }
+
+    #[test]
+    fn crates_and_full_hands_are_not_picked_up() {
+        let settings = Settings {
+            pickup_on_walk: true,
+            ..Default::default()
+        };
+        let mut level = load(TWO_ROOMS, &settings);
+        let position = level.level.player.body.position.clone();
+        level.level.crates = vec![ItemCrate::new(tomato(), position.clone(), Room(0))];
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert_eq!(level.level.crates.len(), 1);
+        // A full inventory leaves the item on the floor
+        level.level.player.inventory.items = vec![Item::Sword, tomato(), Item::Key];
+        level.level.crates = vec![ItemCrate::dropped(tomato(), position, Room(0), false)];
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert_eq!(level.level.crates.len(), 1);
+        // With the setting off only E takes it
+        level.level.player.inventory.items = vec![Item::Sword];
+        level.level.player.inventory.selected = 0;
+        level.update(&walk((0, 0)), &Settings::default(), TICK);
+        assert_eq!(level.level.crates.len(), 1);
+    }
+
+    const TWO_ENTRANCES: &str = "
+rooms:
+- id: 0
+  enter: North
+  enemies: 0
+  doors:
+  - direction: South
+    to: 1
+- id: 1
+  enter: South
+  enemies: 0
+  doors: []
+";
+
+    #[test]
+    fn player_starts_in_the_chosen_entry_room() {
+        let mut entered = HashSet::new();
+        for seed in 0..20 {
+            let settings = Settings {
+                seed: Some(seed),
+                ..Default::default()
+            };
+            let level = load(TWO_ENTRANCES, &settings);
+            let room = level.level.player.body.room;
+            let entrance = level.level.doors.iter().find(|door| door.entrance).unwrap();
+            assert_eq!(entrance.from, room);
+            // The entrance is in the wall of the entry room the player came through
+            let direction = if room == Room(0) {
+                Direction::North
+            } else {
+                Direction::South
+            };
+            assert_eq!(entrance.direction, direction);
+            assert!(level.level.visited.contains(&room.0));
+            entered.insert(room.0);
+        }
+        assert_eq!(entered, HashSet::from([0, 1]));
+    }
+
+    /// Entry room with a guard behind the door, who may be left alive
+    const SNEAKING: &str = "
+leave_alive: true
+rooms:
+- id: 0
+  enter: North
+  enemies: 0
+  doors:
+  - direction: South
+    to: 1
+- id: 1
+  enemies: 1
+  doors: []
+";
+
+    /// Whether standing at the entrance leaves the level
+    fn leaves(level: &mut Level, settings: &Settings) -> bool {
+        level.level.player.body.position.0 = Vec2::new(RATIO_W_H / 2., WALL_SIZE);
+        level.update(&walk((0, 0)), settings, TICK).next
+    }
+
+    #[test]
+    fn leaving_without_a_kill_is_pacifist() {
+        let settings = Settings::default();
+        let mut level = load(SNEAKING, &settings);
+        assert!(leaves(&mut level, &settings));
+        assert!(level.outcome.pacifist);
+        // Guards that must be dead keep the player in
+        let mut level = load(&SNEAKING.replace("leave_alive: true", ""), &settings);
+        assert!(!leaves(&mut level, &settings));
+    }
+
+    #[test]
+    fn empty_hands_ask_for_the_sword() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        level.level.player.inventory = Inventory {
+            items: Vec::new(),
+            selected: 0,
+        };
+        assert!(!leaves(&mut level, &settings));
+        let phrase = level.level.player.body.phrase.as_ref().unwrap();
+        assert_eq!(phrase.text, "I need my sword to leave");
+        level.level.player.inventory = Inventory::new(Item::Sword);
+        assert!(leaves(&mut level, &settings));
+    }
+
+    #[test]
+    fn a_kill_is_not_pacifist_even_after_a_restart() {
+        let settings = Settings::default();
+        let mut level = load(SNEAKING, &settings);
+        level.level.enemies[0].health = Health::Dead;
+        level.update(&walk((0, 0)), &settings, TICK);
+        level.level = level.backup.clone();
+        assert_ne!(level.level.enemies[0].health, Health::Dead);
+        assert!(leaves(&mut level, &settings));
+        assert!(!level.outcome.pacifist);
+    }
+
+    /// Seconds the wounded player waited to heal after the ticks, the guard fighting or not
+    fn healing(out_of_combat: bool, fighting: bool, ticks: u32) -> f32 {
+        let mut settings = Settings::default();
+        settings.heal.out_of_combat = out_of_combat;
+        let mut level = load(SNEAKING, &settings);
+        level.level.player.health = Health::Low;
+        for _ in 0..ticks {
+            let guard = &mut level.level.enemies[0];
+            guard.state = if fighting {
+                EnemyState::Fight(guard.body.position.0, guard.body.form)
+            } else {
+                EnemyState::Idle
+            };
+            level.update(&walk((0, 0)), &settings, TICK);
+        }
+        assert_eq!(level.level.player.health, Health::Low);
+        level.level.player.heal_time
+    }
+
+    #[test]
+    fn healing_waits_for_the_fight_to_end() {
+        assert_eq!(healing(true, true, 60), 0.);
+        assert!((healing(true, false, 60) - 60. * TICK).abs() < 1e-4);
+        // Unless the setting is off
+        assert!((healing(false, true, 60) - 60. * TICK).abs() < 1e-4);
+    }
+
+    #[test]
+    fn level_without_healing_keeps_the_wound() {
+        let settings = Settings::default();
+        let wounded = |yaml: &str| {
+            let mut level = load(yaml, &settings);
+            level.level.player.health = Health::Low;
+            level
+        };
+        let mut level = wounded(&format!("heal: false\n{TWO_ROOMS}"));
+        for _ in 0..60 {
+            level.run(walk((0, 0)), &settings, 20);
+            assert_eq!(level.level.player.health, Health::Low);
+        }
+        // The level may heal faster than the setting
+        let mut level = wounded(&format!("heal: true\nheal_time: 1.\n{TWO_ROOMS}"));
+        level.run(walk((0, 0)), &settings, 70);
+        assert_eq!(level.level.player.health, Health::Full);
+    }
+
+    const GUARDED: &str = "
+rooms:
+- id: 0
+  enter: North
+  enemies: 1
+  doors: []
+";
+
+    /// Plays the guarded room for the ticks at the frame rate, throwing at the guard
+    fn play_at(fps: f32, total: u32) -> Level {
+        let settings = Settings::default();
+        let mut level = load(GUARDED, &settings);
+        level.level.player.body.position.0 = Vec2::new(0.4, 0.5);
+        level.level.player.inventory = Inventory {
+            items: vec![Item::Sword, tomato()],
+            selected: 1,
+        };
+        let guard = &mut level.level.enemies[0];
+        guard.body.position.0 = Vec2::new(1.4, 0.45);
+        guard.post.0 = guard.body.position.0;
+        let mut tick_time = 0.;
+        let mut ticks = 0;
+        let mut first = true;
+        while ticks < total {
+            let frame = frame_ticks(&mut tick_time, 1. / fps).min(total - ticks);
+            let mut input = walk((1, 0));
+            input.player.move_action.sight = Vec2::new(1., 0.);
+            input.player.attack = true;
+            // Standing up is pressed once, in a frame without ticks at high frame rates
+            input.player.crouch = std::mem::take(&mut first);
+            level.run(input, &settings, frame);
+            ticks += frame;
+        }
+        level
+    }
+
+    #[test]
+    fn frame_rate_does_not_change_the_outcome() {
+        let expected = play_at(60., 120);
+        assert!(expected.level.player.visible);
+        assert_eq!(expected.level.enemies[0].health, Health::Dead);
+        for fps in [24., 30., 50., 144., 240.] {
+            let level = play_at(fps, 120);
+            let (player, guard) = (&level.level.player, &level.level.enemies[0]);
+            let expected_guard = &expected.level.enemies[0];
+            assert_eq!(level.time, expected.time, "{fps}");
+            assert_eq!(
+                player.body.position.0, expected.level.player.body.position.0,
+                "{fps}"
+            );
+            assert_eq!(guard.health, expected_guard.health, "{fps}");
+            assert_eq!(guard.dead_time, expected_guard.dead_time, "{fps}");
+            assert_eq!(
+                guard.body.position.0, expected_guard.body.position.0,
+                "{fps}"
+            );
+            assert_eq!(level.level.balls.len(), expected.level.balls.len(), "{fps}");
+        }
+    }
+
+    #[test]
+    fn half_game_speed_halves_the_movement() {
+        // Distance walked in a real second, after speeding up
+        let walked = |time_scale| {
+            let settings = Settings {
+                time_scale,
+                ..Default::default()
+            };
+            let mut level = load(TWO_ROOMS, &settings);
+            level.level.player.body.position.0.x = 0.2;
+            let mut tick_time = 0.;
+            let mut walk_for = |level: &mut Level, frames| {
+                for _ in 0..frames {
+                    let ticks = frame_ticks(&mut tick_time, settings.time_scale() / 60.);
+                    level.run(walk((1, 0)), &settings, ticks);
+                }
+            };
+            walk_for(&mut level, 30);
+            let start = level.level.player.body.position.0.x;
+            walk_for(&mut level, 60);
+            level.level.player.body.position.0.x - start
+        };
+        let (full, half) = (walked(1.), walked(0.5));
+        assert!((full - PLAYER_MAX_SPEED).abs() < 0.02, "{full}");
+        assert!((half - full / 2.).abs() < 0.02, "{half} {full}");
+    }
+
+    #[test]
+    fn restarting_the_level_puts_back_the_config() {
+        let settings = Settings::default();
+        let mut level = load(
+            "
+rooms:
+- id: 0
+  enter: North
+  enemies: 1
+  items:
+  - !Key
+  doors:
+  - direction: South
+    to: 1
+- id: 1
+  enemies: 2
+  items:
+  - !Vegetable
+    name: tomato
+    idx: 0
+    color: [212, 0, 0, 128]
+  doors: []
+",
+            &settings,
+        );
+        // Take the key, drop the sword and kill everyone
+        let key = level
+            .level
+            .crates
+            .iter()
+            .position(|item_crate| item_crate.item == Item::Key);
+        level.level.crates.remove(key.unwrap());
+        level.level.player.inventory = Inventory::new(Item::Key);
+        let position = level.level.player.body.position.clone();
+        level
+            .level
+            .crates
+            .push(ItemCrate::dropped(Item::Sword, position, Room(0), true));
+        for enemy in &mut level.level.enemies {
+            enemy.health = Health::Dead;
+        }
+        level.update(&walk((0, 0)), &settings, TICK);
+        level.backup = level.level.clone();
+        let restart = LevelInput {
+            restart_level: true,
+            ..Default::default()
+        };
+        level.update(&restart, &settings, TICK);
+        let mut crates: Vec<_> = level
+            .level
+            .crates
+            .iter()
+            .map(|item_crate| {
+                (
+                    item_crate.room.0,
+                    item_crate.item.name(),
+                    item_crate.dropped,
+                )
+            })
+            .collect();
+        crates.sort();
+        assert_eq!(
+            crates,
+            [
+                (0, "key".to_owned(), false),
+                (1, "tomato".to_owned(), false)
+            ]
+        );
+        let guards = |room| {
+            level
+                .level
+                .enemies
+                .iter()
+                .filter(|enemy| enemy.body.room == Room(room) && enemy.health != Health::Dead)
+                .count()
+        };
+        assert_eq!((guards(0), guards(1)), (1, 2));
+        assert_eq!(level.level.enemies.len(), 3);
+        assert!(level.level.player.inventory.items == [Item::Sword]);
+    }
+
+    #[test]
+    fn frame_ticks_keep_the_rest() {
+        let mut tick_time = 0.;
+        let ticks: u32 = (0..144)
+            .map(|_| frame_ticks(&mut tick_time, 1. / 144.))
+            .sum();
+        assert!((59..=60).contains(&ticks), "{ticks}");
+        // A stall doesn't run the game in fast forward
+        assert_eq!(frame_ticks(&mut tick_time, 2.), MAX_TICKS);
+        assert!(tick_time <= TICK);
+    }
+
+    #[test]
+    fn presses_wait_for_a_tick_and_are_taken_once() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        let mut input = walk((0, 0));
+        input.player.crouch = true;
+        level.run(input, &settings, 0);
+        assert!(!level.level.player.visible);
+        level.run(walk((0, 0)), &settings, 3);
+        // Toggled once, not once per tick
+        assert!(level.level.player.visible);
+    }
+
+    #[test]
+    fn death_screen_fades_in_over_the_set_time() {
+        let settings = Settings {
+            death_fade: 2.,
+            ..Default::default()
+        };
+        let mut level = load(TWO_ROOMS, &settings);
+        level.level.player.health = Health::Dead;
+        let restart = LevelInput {
+            restart: true,
+            ..Default::default()
+        };
+        let mut previous = 0.;
+        for tick in 1..120 {
+            // Restarting waits for the fade
+            level.update(&restart, &settings, TICK);
+            assert_eq!(level.level.player.health, Health::Dead);
+            let progress = death_progress(level.death_fade, &settings);
+            assert!(progress > previous);
+            assert!((progress - tick as f32 * TICK / 2.).abs() < 1e-4);
+            previous = progress;
+        }
+        level.run(walk((0, 0)), &settings, 5);
+        assert_eq!(death_progress(level.death_fade, &settings), 1.);
+        // Without a fade the screen is there at once
+        let instant = Settings {
+            death_fade: 0.,
+            ..Default::default()
+        };
+        assert_eq!(death_progress(0., &instant), 1.);
+    }
+
+    #[test]
+    fn ghost_replays_by_ticks() {
+        let settings = Settings::default();
+        let mut paths = Vec::new();
+        for fps in [30., 144.] {
+            let mut level = load(TWO_ROOMS, &settings);
+            level.level.player.body.position.0 = Vec2::new(0.4, 0.5);
+            let mut tick_time = 0.;
+            let mut ticks = 0;
+            while ticks < 90 {
+                let frame = frame_ticks(&mut tick_time, 1. / fps).min(90 - ticks);
+                level.run(walk((1, 0)), &settings, frame);
+                ticks += frame;
+            }
+            assert_eq!(level.path.len(), 90);
+            level.level.player.health = Health::Dead;
+            level.death_fade = settings.death_fade;
+            let restart = LevelInput {
+                restart: true,
+                ..Default::default()
+            };
+            level.run(restart, &settings, 1);
+            assert_eq!(level.ghost.1, 0);
+            level.run(LevelInput::default(), &settings, 30);
+            assert_eq!(level.ghost.1, 30);
+            paths.push(level.ghost.0);
+        }
+        let positions = |path: &Vec<(Room, Vec2, Vec2)>| -> Vec<Vec2> {
+            path.iter().map(|(_, position, _)| *position).collect()
+        };
+        assert_eq!(positions(&paths[0]), positions(&paths[1]));
+    }
+
+    #[test]
+    fn peeking_shows_the_next_room_and_holds_the_player() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        let at_door = Vec2::new(RATIO_W_H / 2., 1. - WALL_SIZE - 0.04);
+        level.level.player.body.position.0 = at_door;
+        let peek = LevelInput {
+            peek: true,
+            ..walk((0, 1))
+        };
+        for _ in 0..30 {
+            level.update(&peek, &settings, TICK);
+            assert_eq!(level.peek, Some((Direction::South, Room(1))));
+        }
+        assert_eq!(level.level.player.body.position.0, at_door);
+        assert_eq!(level.level.player.body.room, Room(0));
+        // Letting go walks on through the door
+        level.update(&walk((0, 1)), &settings, TICK);
+        assert_eq!(level.peek, None);
+        assert_eq!(level.level.player.body.room, Room(1));
+        // Away from a door there is nothing to peek through
+        level.level.player.body.position.0 = Vec2::new(0.4, 0.5);
+        level.update(&peek, &settings, TICK);
+        assert_eq!(level.peek, None);
+    }
+
+    /// Two guards, the first `fighting` of them fight the wounded player
+    fn cornered(health: Health, fighting: usize) -> Level {
+        let mut level = load(
+            "
+last_stand: true
+rooms:
+- id: 0
+  enter: North
+  enemies: 2
+  doors: []
+",
+            &Settings::default(),
+        );
+        level.level.player.health = health;
+        let player = &level.level.player.body;
+        let fight = EnemyState::Fight(player.position.0, player.form);
+        for enemy in level.level.enemies.iter_mut().take(fighting) {
+            enemy.state = fight.clone();
+        }
+        level
+    }
+
+    #[test]
+    fn last_stand_slows_down_once_per_room() {
+        let settings = Settings::default();
+        let mut level = cornered(Health::Low, 2);
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert!(level.level.slow_motion > 0.);
+        assert_eq!(level.level.last_stands, [Room(0)]);
+        // Not again in the same room
+        level.level.slow_motion = 0.;
+        for enemy in &mut level.level.enemies {
+            enemy.state = EnemyState::Fight(Vec2::ZERO, body_form());
+        }
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert_eq!(level.level.slow_motion, 0.);
+    }
+
+    #[test]
+    fn last_stand_needs_a_wound_and_several_guards() {
+        let settings = Settings::default();
+        for (health, fighting) in [(Health::Full, 2), (Health::Low, 1), (Health::Low, 0)] {
+            let mut level = cornered(health, fighting);
+            level.update(&walk((0, 0)), &settings, TICK);
+            assert_eq!(level.level.slow_motion, 0.);
+            assert!(level.level.last_stands.is_empty());
+        }
+        // Off unless the level asks for it
+        let mut level = cornered(Health::Low, 2);
+        level.last_stand = false;
+        level.update(&walk((0, 0)), &settings, TICK);
+        assert_eq!(level.level.slow_motion, 0.);
+    }
+
+    fn guard(x: f32, y: f32) -> Enemy {
+        let level = load(GUARDED, &Settings::default());
+        let mut guard = level.level.enemies[0].clone();
+        guard.body.position.0 = Vec2::new(x, y);
+        guard.post.0 = guard.body.position.0;
+        guard
+    }
+
+    /// Action of the guard with nothing else around
+    fn act(guard: &mut Enemy, player: &mut Player) -> MoveAction {
+        let surroundings = Surroundings {
+            decoys: &[],
+            crates: &[],
+            doors: &[],
+        };
+        enemy_action(
+            guard,
+            player,
+            &surroundings,
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &Settings::default(),
+            TICK,
+        )
+    }
+
+    #[test]
+    fn patrol_advances_and_wraps() {
+        let mut player = player(0.4, 0.5);
+        player.body.room = Room(1);
+        let mut guard = guard(0.5, 0.5);
+        guard.patrol = vec![Vec2::new(0.5, 0.5), Vec2::new(1., 0.5), Vec2::new(1., 0.8)];
+        // Standing at the first point heads to the second
+        let action = act(&mut guard, &mut player);
+        assert_eq!(guard.waypoint, 1);
+        assert_eq!(action.move_direction, (1, 0));
+        // Far from the point it keeps going
+        guard.body.position.0 = Vec2::new(0.7, 0.5);
+        act(&mut guard, &mut player);
+        assert_eq!(guard.waypoint, 1);
+        // The last point wraps around to the first
+        guard.waypoint = 2;
+        guard.body.position.0 = Vec2::new(1., 0.8);
+        let action = act(&mut guard, &mut player);
+        assert_eq!(guard.waypoint, 0);
+        assert_eq!(action.move_direction, (-1, -1));
+    }
+
+    #[test]
+    fn guard_without_patrol_returns_to_the_post() {
+        let mut player = player(0.4, 0.5);
+        player.body.room = Room(1);
+        let mut guard = guard(0.5, 0.5);
+        guard.body.position.0 = Vec2::new(0.8, 0.5);
+        let action = act(&mut guard, &mut player);
+        assert_eq!(action.move_direction, (-1, 0));
+        assert_eq!(action.sight, Vec2::new(1., 0.));
+    }
+
+    #[test]
+    fn searching_guard_faces_the_last_seen_position() {
+        let mut player = player(0.6, 0.8);
+        let mut guard = guard(1., 0.5);
+        guard.state = EnemyState::Fight(player.body.position.0, player.body.form);
+        // The player slips away into the next room
+        player.body.room = Room(1);
+        let action = act(&mut guard, &mut player);
+        let remembered = Vec2::new(0.6, 0.8);
+        assert!(matches!(guard.state, EnemyState::LastSeen(at, _) if at == remembered));
+        let towards = (remembered - guard.body.position.0).normalize();
+        assert!(action.sight.distance(towards) < 1e-5);
+        let (x, y) = action.move_direction;
+        assert!(Vec2::new(x as f32, y as f32).dot(towards) > 0.);
+        // Standing on the spot keeps looking the same way
+        guard.body.position.0 = remembered;
+        guard.body.sight.0 = towards;
+        let action = act(&mut guard, &mut player);
+        assert_eq!(action.sight, towards);
+    }
+
+    #[test]
+    fn guard_does_not_see_behind() {
+        let mut guard = guard(1., 0.5);
+        // Visible player at medium range right behind the guard looking east
+        let mut behind = player(0.7, 0.5);
+        for _ in 0..60 {
+            act(&mut guard, &mut behind);
+        }
+        assert!(matches!(guard.state, EnemyState::Idle));
+        assert_eq!(guard.alertness, 0.);
+        // The same distance in front is noticed
+        let mut ahead = player(1.3, 0.5);
+        act(&mut guard, &mut ahead);
+        assert!(matches!(guard.state, EnemyState::Suspicious(_)));
+    }
+
+    #[test]
+    fn shout_reaches_guards_within_the_alert_radius() {
+        let spotter = Vec2::new(0.4, 0.5);
+        let mut guards: Vec<_> = [0.8, 1.2, 0.6]
+            .into_iter()
+            .map(|x| {
+                let mut guard = guard(x, 0.5);
+                guard.alert_radius = 0.6;
+                guard
+            })
+            .collect();
+        guards[2].body.room = Room(1);
+        let target = Vec2::new(0.2, 0.3);
+        alert_allies(&mut guards, &[(Room(0), spotter, target)]);
+        assert!(matches!(guards[0].state, EnemyState::LastSeen(at, _) if at == target));
+        // Too far away or behind a wall
+        assert!(matches!(guards[1].state, EnemyState::Idle));
+        assert!(matches!(guards[2].state, EnemyState::Idle));
+    }
+
+    /// Ticks a guard right next to the player takes to land the second slash
+    fn second_slash(difficulty: Difficulty) -> u32 {
+        let settings = Settings {
+            difficulty,
+            ..Default::default()
+        };
+        let mut level = load(GUARDED, &Settings::default());
+        level.level.player.body.position.0 = Vec2::new(0.8, 0.5);
+        let guard = &mut level.level.enemies[0];
+        guard.body.position.0 = Vec2::new(0.85, 0.5);
+        guard.post.0 = guard.body.position.0;
+        let player = &level.level.player.body;
+        guard.state = EnemyState::Fight(player.position.0, player.form);
+        (1..600)
+            .find(|_| {
+                step(&mut level.level, &walk((0, 0)), &settings, None, TICK);
+                level.level.player.health == Health::Critical
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn easy_guards_slash_slower() {
+        let easy = second_slash(Difficulty::Easy);
+        let normal = second_slash(Difficulty::Normal);
+        let hard = second_slash(Difficulty::Hard);
+        assert!(easy > normal && normal > hard, "{easy} {normal} {hard}");
+    }
+
+    fn ball(from: Vec2, direction: Vec2) -> Ball {
+        Ball {
+            position: Position(from),
+            radius: BALL_RADIUS,
+            velocity: Velocity(direction * BALL_SPEED),
+            lifetime: 0.,
+            room: Room(0),
+            item: tomato(),
+            hostile: false,
+        }
+    }
+
+    #[test]
+    fn guards_are_drawn_in_one_batch_per_texture() {
+        let guards: Vec<_> = (0..5)
+            .map(|n| {
+                let mut guard = guard(0.3 + 0.25 * n as f32, 0.5);
+                guard.stain = Some(RED);
+                guard.carries = Some(tomato());
+                guard
+            })
+            .collect();
+        let screen = Screen {
+            x: 0.,
+            y: 0.,
+            width: 1280.,
+            height: 720.,
+        };
+        let sprites = enemy_sprites(&guards, Room(0), &Settings::default(), &screen);
+        assert_eq!(sprites.len(), 15);
+        let batches = 1 + sprites
+            .windows(2)
+            .filter(|pair| pair[0].texture != pair[1].texture)
+            .count();
+        assert_eq!(batches, 2);
+        assert!(enemy_sprites(&guards, Room(1), &Settings::default(), &screen).is_empty());
+    }
+
+    #[test]
+    fn fast_ball_still_hits_the_guard() {
+        let settings = Settings::default();
+        let mut level = load(GUARDED, &settings);
+        level.level.player.body.room = Room(1);
+        level.level.enemies[0].body.position.0 = Vec2::new(1.2, 0.5);
+        level.level.enemies[0].post.0 = Vec2::new(1.2, 0.5);
+        level
+            .level
+            .balls
+            .push(ball(Vec2::new(0.4, 0.5), Vec2::new(1., 0.)));
+        // A whole second passes in one step, the ball would end up far behind the guard
+        step(
+            &mut level.level,
+            &LevelInput::default(),
+            &settings,
+            None,
+            1.,
+        );
+        assert_eq!(level.level.enemies[0].health, Health::Dead);
+        assert!(level.level.balls.is_empty());
+    }
+
+    #[test]
+    fn guard_checks_the_decoy_until_it_is_gone() {
+        let settings = Settings::default();
+        let mut level = load(GUARDED, &settings);
+        level.level.player.body.room = Room(1);
+        let post = Vec2::new(1.2, 0.5);
+        level.level.enemies[0].body.position.0 = post;
+        level.level.enemies[0].post.0 = post;
+        let decoy = Vec2::new(0.8, 0.5);
+        level.level.decoys.push(Decoy {
+            item: tomato(),
+            position: Position(decoy),
+            room: Room(0),
+            time: 0.9,
+        });
+        let tick = |level: &mut Level| {
+            step(
+                &mut level.level,
+                &LevelInput::default(),
+                &settings,
+                None,
+                TICK,
+            );
+        };
+        for _ in 0..30 {
+            tick(&mut level);
+        }
+        let guard = &level.level.enemies[0];
+        assert!(matches!(guard.state, EnemyState::LastSeen(at, _) if at == decoy));
+        let checking = guard.body.position.0;
+        assert!(checking.x < post.x - 0.1);
+        for _ in 0..30 {
+            tick(&mut level);
+        }
+        assert!(level.level.decoys.is_empty());
+        assert!(matches!(level.level.enemies[0].state, EnemyState::Idle));
+        for _ in 0..60 {
+            tick(&mut level);
+        }
+        let back = level.level.enemies[0].body.position.0;
+        assert!(back.distance(post) < checking.distance(post));
+    }
+
+    #[test]
+    fn fast_ball_stops_at_the_wall() {
+        let settings = Settings::default();
+        let mut level = load(TWO_ROOMS, &settings);
+        level
+            .level
+            .balls
+            .push(ball(Vec2::new(0.4, 0.5), Vec2::new(0., -1.)));
+        let step = step(
+            &mut level.level,
+            &LevelInput::default(),
+            &settings,
+            None,
+            1.,
+        );
+        assert!(level.level.balls.is_empty());
+        let stain = &level.level.stains[0];
+        assert_eq!(stain.direction, Direction::North);
+        assert!((stain.position.0.x - 0.4).abs() < 1e-3);
+        assert!(step.sounds.iter().any(|sound| sound.name == "splat"));
+    }
+
+    #[test]
+    fn thrown_vegetable_breaks_the_window() {
+        let settings = Settings::default();
+        let mut level = load(
+            "
+rooms:
+- id: 0
+  enter: North
+  enemies: 0
+  doors:
+  - direction: South
+    to: 1
+    breakable: true
+- id: 1
+  enemies: 0
+  doors: []
+",
+            &settings,
+        );
+        let window = |level: &Level| {
+            level
+                .level
+                .doors
+                .iter()
+                .find(|door| !door.entrance)
+                .unwrap()
+                .closed
+        };
+        assert!(window(&level));
+        // The wall next to it holds
+        level
+            .level
+            .balls
+            .push(ball(Vec2::new(0.4, 0.5), Vec2::new(0., 1.)));
+        step(
+            &mut level.level,
+            &LevelInput::default(),
+            &settings,
+            None,
+            1.,
+        );
+        assert!(window(&level));
+        level
+            .level
+            .balls
+            .push(ball(Vec2::new(RATIO_W_H / 2., 0.5), Vec2::new(0., 1.)));
+        let step = step(
+            &mut level.level,
+            &LevelInput::default(),
+            &settings,
+            None,
+            1.,
+        );
+        assert!(!window(&level));
+        assert!(step.sounds.iter().any(|sound| sound.name == "glass"));
+        // The way to the next room is open
+        level.level.player.body.position.0 = Vec2::new(RATIO_W_H / 2., 1. - WALL_SIZE - 0.04);
+        level.update(&walk((0, 1)), &settings, TICK);
+        assert_eq!(level.level.player.body.room, Room(1));
+    }
+
+    /// Where everything is placed, rounded so that it can be compared
+    fn layout(level: &Level) -> Vec<(u8, [u32; 2])> {
+        let point =
+            |room: Room, position: Vec2| (room.0, [position.x, position.y].map(f32::to_bits));
+        let inner = &level.level;
+        std::iter::once(point(inner.player.body.room, inner.player.body.position.0))
+            .chain(
+                inner
+                    .enemies
+                    .iter()
+                    .map(|enemy| point(enemy.body.room, enemy.body.position.0)),
+            )
+            .chain(
+                inner
+                    .crates
+                    .iter()
+                    .map(|item_crate| point(item_crate.room, item_crate.position.0)),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_layout() {
+        let yaml = std::fs::read_to_string("assets/level_4.yaml").unwrap();
+        let settings = Settings {
+            seed: Some(1912),
+            ..Default::default()
+        };
+        let first = layout(&load(&yaml, &settings));
+        assert!(first.len() > 5);
+        for _ in 0..20 {
+            assert_eq!(layout(&load(&yaml, &settings)), first);
+        }
+        let other = Settings {
+            seed: Some(2001),
+            ..Default::default()
+        };
+        assert_ne!(layout(&load(&yaml, &other)), first);
+    }
+
+    #[test]
+    fn hints_follow_the_bindings() {
+        let mut keys = KeyBindings::default();
+        assert_eq!(use_hint(&keys), "E to use");
+        assert_eq!(death_hint(&keys), "You're dead. Press R to continue");
+        keys.interact = KeyCode::F;
+        keys.restart = KeyCode::Space;
+        assert_eq!(use_hint(&keys), "F to use");
+        assert_eq!(death_hint(&keys), "You're dead. Press Space to continue");
+        assert_eq!(skip_hint(&keys), "Space/Enter to skip");
+        keys.skip = vec![KeyCode::Escape];
+        assert_eq!(skip_hint(&keys), "Escape to skip");
+    }
+
+    #[test]
+    fn room_graph_connects_both_sides_of_a_door() {
+        let level = config(
+            "
+rooms:
+- id: 0
+  enter: West
+  enemies: 0
+  doors:
+  - direction: East
+    to: 1
+    closed: true
+- id: 1
+  enemies: 0
+  doors:
+  - direction: South
+    to: 2
+- id: 2
+  enemies: 0
+  doors: []
+",
+        );
+        let graph = {
+            let _rng = RNG.lock().unwrap_or_else(|err| err.into_inner());
+            build_room_graph(&level).unwrap()
+        };
+        assert_eq!(graph.entry.id, 0);
+        assert_eq!(graph.enter, Direction::West);
+        let connections: Vec<_> = graph
+            .rooms
+            .iter()
+            .map(|(id, doors)| {
+                let doors: Vec<_> = doors
+                    .iter()
+                    .map(|(direction, room, closed)| (*direction, room.id, *closed))
+                    .collect();
+                (*id, doors)
+            })
+            .collect();
+        assert_eq!(
+            connections,
+            [
+                (0, vec![(Direction::East, 1, true)]),
+                (
+                    1,
+                    vec![(Direction::West, 0, true), (Direction::South, 2, false)]
+                ),
+                (2, vec![(Direction::North, 1, false)]),
+            ]
+        );
+        let level = config(
+            "
+rooms:
+- id: 0
+  enemies: 0
+  doors: []
+",
+        );
+        assert!(matches!(build_room_graph(&level), Err(LevelError::NoEntry)));
+    }
+
+    #[test]
+    fn validate_rejects_thrown_non_vegetables() {
+        let level = config(
+            "
+rooms:
+- id: 0
+  enter: North
+  enemies: 1
+  doors: []
+  throws:
+  - !Key
+",
+        );
+        assert!(matches!(validate(&level), Err(LevelError::NotThrowable(0))));
+    }
+
+    #[test]
+    fn validate_accepts_thrown_vegetables() {
+        let level = config(
+            "
+rooms:
+- id: 0
+  enter: North
+  enemies: 1
+  doors: []
+  throws:
+  - !Vegetable
+    name: tomato
+    idx: 0
+    color: [212, 0, 0, 128]
+",
+        );
+        assert!(validate(&level).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_inverted_enemy_range() {
+        let level = config(
+            "
+rooms:
+- id: 0
+  enter: North
+  enemies:
+    min: 3
+    max: 1
+  doors: []
+",
+        );
+        assert!(matches!(validate(&level), Err(LevelError::EnemyRange(0))));
+    }
+
+    #[test]
+    fn validate_rejects_items_of_guards_that_may_not_spawn() {
+        let room = |enemies| {
+            config(&format!(
+                "
+rooms:
+- id: 0
+  enter: North
+  enemies: {enemies}
+  carried:
+  - !Key
+  - !Sword
+  doors: []
+"
+            ))
+        };
+        assert!(matches!(
+            validate(&room("{min: 1, max: 3}")),
+            Err(LevelError::CarriedItems(0))
+        ));
+        assert!(matches!(
+            validate(&room("1")),
+            Err(LevelError::CarriedItems(0))
+        ));
+        assert!(validate(&room("{min: 2, max: 3}")).is_ok());
+        assert!(validate(&room("2")).is_ok());
+    }
+
+    #[test]
+    fn enemy_count_covers_the_full_range() {
+        let _rng = RNG.lock().unwrap_or_else(|err| err.into_inner());
+        macroquad::rand::srand(7);
+        for _ in 0..100 {
+            // The whole u8 range doesn't overflow
+            Enemies::Range { min: 0, max: 255 }.count(Difficulty::Normal);
+            let count = Enemies::Range { min: 250, max: 255 }.count(Difficulty::Normal);
+            assert!((250..=255).contains(&count));
+        }
+        assert_eq!(
+            Enemies::Range { min: 4, max: 4 }.count(Difficulty::Normal),
+            4
+        );
+    }
+
+    #[test]
+    fn hard_is_biased_to_the_max() {
+        let range = Enemies::Range { min: 0, max: 10 };
+        let _rng = RNG.lock().unwrap_or_else(|err| err.into_inner());
+        let mean = |difficulty| {
+            macroquad::rand::srand(11);
+            (0..1000)
+                .map(|_| u32::from(range.count(difficulty)))
+                .sum::<u32>() as f32
+                / 1000.
+        };
+        // Hard also adds a guard, which alone is not the bias
+        let easy = mean(Difficulty::Easy);
+        let normal = mean(Difficulty::Normal);
+        let hard = mean(Difficulty::Hard) - f32::from(Difficulty::Hard.params().extra_guards);
+        assert!(easy < normal && normal < hard, "{easy} {normal} {hard}");
+        assert!(hard > 6., "{hard}");
     }
 }
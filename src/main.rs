@@ -1,66 +1,361 @@
 #![warn(clippy::semicolon_if_nothing_returned)]
-use assets::SCENES;
-use graphics::{draw_centered_txt, draw_cursor, draw_rect, get_screen_size, Screen};
-use level::{draw_level, update_level, Level};
-use scene::{draw_scene, update_scene, Scene};
+use graphics::{draw_centered_txt, draw_cursor, draw_rect, get_lines, get_screen_size, Screen};
+use level::{draw_level, frame_ticks, update_level, Level, Outcome, TICK};
+use scene::{draw_scene, update_scene, Scene, AUTO_ADVANCE_TIME};
 
 use macroquad::{
-    audio::{play_sound, stop_sound, PlaySoundParams, Sound},
+    audio::{play_sound, set_sound_volume, stop_sound, PlaySoundParams, Sound},
     prelude::*,
 };
 
-use crate::assets::Assets;
+use crate::{
+    assets::{active_campaign, Assets, Campaign},
+    menu::Menu,
+    save::SaveData,
+    settings::{Difficulty, KeyBindings, Settings, VOLUME_STEP},
+};
 
 mod assets;
+mod camera;
 mod graphics;
 mod level;
+mod menu;
+mod save;
 mod scene;
+mod settings;
 
 pub const RATIO_W_H: f32 = 16. / 9.;
+/// Master volume change for one press in the settings menu
+pub const MENU_VOLUME_STEP: f32 = 0.25;
+
+/// Part of the music volume left while the game is paused
+pub const PAUSED_MUSIC: f32 = 0.25;
+
+/// Pause of a level or a scene and the music volume that goes with it. Sound effects
+/// only come from updates, which stop while paused, so they are silent too
+#[derive(Default)]
+pub struct Pause {
+    paused: bool,
+}
+
+impl Pause {
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pauses or resumes and returns the music volume to set. The playing track only
+    /// changes its volume, so it never starts a second time
+    pub fn toggle(&mut self, settings: &Settings) -> f32 {
+        self.paused = !self.paused;
+        self.music_volume(settings)
+    }
 
+    pub fn music_volume(&self, settings: &Settings) -> f32 {
+        if self.paused {
+            settings.music_volume() * PAUSED_MUSIC
+        } else {
+            settings.music_volume()
+        }
+    }
+}
+
+/// States carry the times of the finished levels to show them at the end
 pub enum State {
-    Scene(usize, Scene),
-    Battle(usize, Level),
-    End(usize),
+    Menu(Menu<MenuAction>),
+    Scene(usize, Scene, Vec<f32>),
+    Battle(usize, Level, Vec<f32>),
+    End(usize, Vec<f32>),
 }
 
 #[macroquad::main("Cooking thief")]
 async fn main() {
     show_mouse(false);
 
-    let assets = Assets::load().await;
-    let mut state = State::Scene(0, assets.scenes[0].clone());
-    let mut sound = assets.sounds["village"];
+    let assets = match Assets::load().await {
+        Ok(assets) => assets,
+        Err(err) => {
+            error!("Can't load assets: {err}");
+            show_error(&format!("Can't load assets: {err}")).await;
+            return;
+        }
+    };
+    let mut settings = Settings {
+        keys: KeyBindings::load(std::fs::read_to_string("keys.yaml").ok().as_deref()),
+        // Set to reproduce the placement of guards and crates from a bug report
+        seed: std::env::var("COOKING_THIEF_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok()),
+        ..Settings::load(std::fs::read_to_string("settings.yaml").ok().as_deref())
+    };
+    rand::srand(miniquad::date::now() as u64);
+    let mut state = State::Menu(main_menu(&assets.campaigns, &settings));
+    let mut sound = assets.sound("village");
     play_sound(
         sound.clone(),
         PlaySoundParams {
             looped: true,
-            volume: 0.75,
+            volume: settings.music_volume(),
         },
     );
 
+    let mut pause = Pause::default();
+    // Time not simulated yet, the game runs in fixed ticks
+    let mut tick_time = 0.;
     loop {
         let dt = get_frame_time();
         let screen = get_screen_size(screen_width(), screen_height());
 
-        update(&mut state, &screen, &assets, &mut sound, dt);
+        if !matches!(state, State::Menu(_))
+            && (is_key_pressed(KeyCode::Escape) || is_key_pressed(settings.keys.pause))
+        {
+            set_sound_volume(sound, pause.toggle(&settings));
+        }
+        let volume_step = if is_key_pressed(settings.keys.volume_up) {
+            VOLUME_STEP
+        } else if is_key_pressed(settings.keys.volume_down) {
+            -VOLUME_STEP
+        } else {
+            0.
+        };
+        if volume_step != 0. {
+            settings.change_volume(volume_step);
+            // The playing track follows without restarting
+            set_sound_volume(sound, pause.music_volume(&settings));
+        }
+        // Music goes on quieter, but nothing else moves
+        if !pause.paused() {
+            let ticks = frame_ticks(&mut tick_time, dt * settings.time_scale());
+            update(
+                &mut state,
+                &screen,
+                &assets,
+                &mut settings,
+                &mut sound,
+                ticks,
+            );
+        }
+
+        draw(&screen, &state, &assets, &settings, pause.paused());
 
-        draw(&screen, &state, &assets);
+        next_frame().await;
+    }
+}
 
+/// Shows the error until Escape or Enter is pressed, e.g. when a modded asset is broken
+async fn show_error(text: &str) {
+    loop {
+        if is_key_pressed(KeyCode::Escape) || is_key_pressed(KeyCode::Enter) {
+            return;
+        }
+        clear_background(BLACK);
+        let screen = get_screen_size(screen_width(), screen_height());
+        let (lines, _) = get_lines(&screen, RATIO_W_H - 0.2, 0.05, text);
+        for (n, line) in lines.iter().enumerate() {
+            draw_centered_txt(&screen, line, 0.4 + 0.07 * n as f32, 0.05, WHITE);
+        }
+        draw_centered_txt(&screen, "Press Escape to quit", 0.9, 0.05, GRAY);
         next_frame().await;
     }
 }
+
+#[derive(Clone, Copy)]
+pub enum MenuAction {
+    Start,
+    Continue,
+    Campaign,
+    Difficulty,
+    Settings,
+    Quit,
+    // Rows of the settings menu
+    Volume,
+    GameSpeed,
+    Captions,
+    AutoAdvance,
+    Back,
+}
+
+/// Saved progress, a missing, broken or outdated save starts a new run
+fn saved_progress(campaigns: &[Campaign]) -> Option<SaveData> {
+    let data = save::load(&save::save_path()?).ok()?;
+    let campaign = campaigns.get(data.campaign)?;
+    ((data.level > 0 || data.battle) && data.level < campaign.chapters.len()).then_some(data)
+}
+
+/// Failing to save only loses the progress
+fn write_save(state: &crate::State, settings: &Settings) {
+    if let Some(path) = save::save_path() {
+        if let Err(err) = save::save(state, settings.difficulty, settings.campaign, &path) {
+            warn!("Can't save progress: {err}");
+        }
+    }
+}
+
+fn difficulty_label(difficulty: Difficulty) -> String {
+    format!("Difficulty: {}", difficulty.name())
+}
+
+fn campaign_label(campaign: &Campaign) -> String {
+    format!("{} by {}", campaign.title, campaign.author)
+}
+
+fn main_menu(campaigns: &[Campaign], settings: &Settings) -> Menu<MenuAction> {
+    Menu::new(
+        vec![
+            ("Start".to_owned(), MenuAction::Start, true),
+            (
+                "Continue".to_owned(),
+                MenuAction::Continue,
+                saved_progress(campaigns).is_some(),
+            ),
+            (
+                campaign_label(active_campaign(campaigns, settings.campaign)),
+                MenuAction::Campaign,
+                campaigns.len() > 1,
+            ),
+            (
+                difficulty_label(settings.difficulty),
+                MenuAction::Difficulty,
+                true,
+            ),
+            ("Settings".to_owned(), MenuAction::Settings, true),
+            ("Quit".to_owned(), MenuAction::Quit, true),
+        ],
+        0.45,
+    )
+}
+
+fn on_off(name: &str, on: bool) -> String {
+    format!("{name}: {}", if on { "On" } else { "Off" })
+}
+
+fn volume_label(settings: &Settings) -> String {
+    format!("Volume: {}%", (settings.master_volume * 100.).round())
+}
+
+fn game_speed_label(settings: &Settings) -> String {
+    format!("Game speed: {}x", settings.time_scale())
+}
+
+fn settings_menu(settings: &Settings) -> Menu<MenuAction> {
+    Menu::new(
+        vec![
+            (volume_label(settings), MenuAction::Volume, true),
+            (game_speed_label(settings), MenuAction::GameSpeed, true),
+            (
+                on_off("Captions", settings.captions),
+                MenuAction::Captions,
+                true,
+            ),
+            (
+                on_off("Auto-advance scenes", settings.auto_advance.is_some()),
+                MenuAction::AutoAdvance,
+                true,
+            ),
+            ("Back".to_owned(), MenuAction::Back, true),
+        ],
+        0.55,
+    )
+}
+
+fn chapter_state(campaign: &Campaign, num: usize, times: Vec<f32>) -> crate::State {
+    let mut scene = campaign.chapters[num].scene.clone();
+    scene.start(Outcome::default());
+    crate::State::Scene(num, scene, times)
+}
+
 pub fn update(
     state: &mut crate::State,
     screen: &Screen,
     assets: &Assets,
+    settings: &mut Settings,
     sound: &mut Sound,
-    dt: f32,
+    ticks: u32,
 ) {
+    let dt = ticks as f32 * TICK;
+    let campaigns = &assets.campaigns;
+    if let crate::State::Menu(menu) = state {
+        match menu.update(screen) {
+            Some(MenuAction::Start) => {
+                *state =
+                    chapter_state(active_campaign(campaigns, settings.campaign), 0, Vec::new());
+                write_save(state, settings);
+            }
+            Some(MenuAction::Continue) => {
+                let data = saved_progress(campaigns).unwrap_or(SaveData {
+                    level: 0,
+                    battle: false,
+                    difficulty: settings.difficulty,
+                    campaign: settings.campaign,
+                    times: Vec::new(),
+                });
+                settings.difficulty = data.difficulty;
+                settings.campaign = data.campaign;
+                let campaign = active_campaign(campaigns, settings.campaign);
+                *state = chapter_state(campaign, data.level, data.times);
+                // The battle starts right after its scene
+                if data.battle {
+                    change_state(state, assets, settings, sound);
+                }
+            }
+            Some(MenuAction::Campaign) => {
+                settings.campaign = (settings.campaign + 1) % campaigns.len();
+                menu.items[menu.focused].0 =
+                    campaign_label(active_campaign(campaigns, settings.campaign));
+            }
+            Some(MenuAction::Difficulty) => {
+                let all = Difficulty::ALL;
+                let next = all
+                    .iter()
+                    .position(|&difficulty| difficulty == settings.difficulty)
+                    .map_or(0, |n| (n + 1) % all.len());
+                settings.difficulty = all[next];
+                menu.items[menu.focused].0 = difficulty_label(settings.difficulty);
+            }
+            Some(MenuAction::Settings) => *menu = settings_menu(settings),
+            Some(MenuAction::Quit) => std::process::exit(0),
+            Some(MenuAction::Volume) => {
+                // Goes around from full volume to silence
+                settings.master_volume = if settings.master_volume >= 1. {
+                    0.
+                } else {
+                    (settings.master_volume + MENU_VOLUME_STEP).min(1.)
+                };
+                set_sound_volume(*sound, settings.music_volume());
+                menu.items[menu.focused].0 = volume_label(settings);
+            }
+            Some(MenuAction::GameSpeed) => {
+                settings.time_scale = settings.next_time_scale();
+                menu.items[menu.focused].0 = game_speed_label(settings);
+            }
+            Some(MenuAction::Captions) => {
+                settings.captions = !settings.captions;
+                menu.items[menu.focused].0 = on_off("Captions", settings.captions);
+            }
+            Some(MenuAction::AutoAdvance) => {
+                settings.auto_advance = match settings.auto_advance {
+                    Some(_) => None,
+                    None => Some(AUTO_ADVANCE_TIME),
+                };
+                menu.items[menu.focused].0 =
+                    on_off("Auto-advance scenes", settings.auto_advance.is_some());
+            }
+            Some(MenuAction::Back) => {
+                *menu = main_menu(campaigns, settings);
+                menu.focused = menu
+                    .items
+                    .iter()
+                    .position(|item| matches!(item.1, MenuAction::Settings))
+                    .unwrap_or(0);
+            }
+            None => {}
+        }
+        return;
+    }
     let next = match state {
-        crate::State::Scene(_, scene) => update_scene(scene, dt),
-        crate::State::Battle(_, level) => update_level(level, screen, assets, dt),
-        crate::State::End(pos) => {
+        crate::State::Menu(_) => false,
+        crate::State::Scene(_, scene, _) => update_scene(scene, screen, settings.auto_advance, dt),
+        crate::State::Battle(_, level, _) => update_level(level, screen, assets, settings, ticks),
+        crate::State::End(pos, _) => {
             let forward = is_key_pressed(KeyCode::Space)
                 || is_key_pressed(KeyCode::Enter)
                 || is_key_pressed(KeyCode::D)
@@ -75,54 +370,135 @@ pub fn update(
         }
     };
     if next {
-        change_state(state, assets, sound);
+        change_state(state, assets, settings, sound);
     }
 }
 
-fn change_state(state: &mut crate::State, assets: &Assets, sound: &mut Sound) {
+fn change_state(state: &mut crate::State, assets: &Assets, settings: &Settings, sound: &mut Sound) {
+    let campaign = active_campaign(&assets.campaigns, settings.campaign);
     stop_sound(sound.clone());
     *state = match state {
-        crate::State::Scene(num, _) => {
-            let config = assets.levels.get(*num).unwrap();
-            *sound = assets.sounds["stealth"];
-
-            crate::State::Battle(*num, Level::load(config))
+        crate::State::Scene(num, _, times) => {
+            let times = std::mem::take(times);
+            if let Some(chapter) = campaign.chapters.get(*num) {
+                *sound = assets.sound("stealth");
+                crate::State::Battle(*num, Level::load(&chapter.level, settings), times)
+            } else {
+                *sound = assets.sound("thief_at_the_kitchen");
+                crate::State::End(0, times)
+            }
         }
-        crate::State::Battle(num, _) => {
+        crate::State::Battle(num, level, times) => {
+            let mut times = std::mem::take(times);
+            times.push(level.time);
             let new_num = *num + 1;
-            if new_num < SCENES.len() {
-                *sound = assets.sounds["village"];
-                crate::State::Scene(new_num, assets.scenes[new_num].clone())
+            if let Some(chapter) = campaign.chapters.get(new_num) {
+                *sound = assets.sound("village");
+                let mut scene = chapter.scene.clone();
+                scene.start(level.outcome);
+                crate::State::Scene(new_num, scene, times)
             } else {
-                *sound = assets.sounds["thief_at_the_kitchen"];
-                crate::State::End(0)
+                *sound = assets.sound("thief_at_the_kitchen");
+                crate::State::End(0, times)
             }
         }
-        crate::State::End(_) => std::process::exit(0),
+        crate::State::End(_, _) | crate::State::Menu(_) => std::process::exit(0),
     };
+    write_save(state, settings);
     play_sound(
         sound.clone(),
         PlaySoundParams {
             looped: true,
-            volume: 0.75,
+            volume: settings.music_volume(),
         },
     );
 }
 
-pub fn draw(screen: &Screen, state: &crate::State, assets: &Assets) {
+pub fn draw(
+    screen: &Screen,
+    state: &crate::State,
+    assets: &Assets,
+    settings: &Settings,
+    paused: bool,
+) {
     clear_background(BLACK);
     draw_rectangle(screen.x, screen.y, screen.width, screen.height, WHITE);
     match state {
-        crate::State::Scene(_, scene) => draw_scene(scene, assets, screen),
-        crate::State::Battle(_, level) => draw_level(level, assets, screen),
-        crate::State::End(pos) => {
+        crate::State::Menu(menu) => {
+            draw_texture_ex(
+                assets.images["back"],
+                screen.x,
+                screen.y,
+                WHITE,
+                DrawTextureParams {
+                    dest_size: Some(Vec2::new(screen.width, screen.height)),
+                    ..Default::default()
+                },
+            );
+            draw_rect(
+                screen,
+                0.,
+                0.,
+                RATIO_W_H,
+                1.,
+                Color::from_rgba(0, 0, 0, 128),
+            );
+            draw_centered_txt(screen, "Cooking thief", 0.35, 0.12, WHITE);
+            menu.draw(screen);
+        }
+        crate::State::Scene(_, scene, _) => draw_scene(scene, assets, screen),
+        crate::State::Battle(_, level, _) => draw_level(level, assets, settings, screen),
+        crate::State::End(pos, times) => {
             draw_rect(screen, 0., 0., RATIO_W_H, 1., BLACK);
-            let start = 0.5 - 0.04 * assets.end[*pos].len() as f32;
-            for (n, line) in assets.end[*pos].iter().enumerate() {
+            let mut lines = assets.end[*pos].clone();
+            // Run summary goes under the last page
+            if *pos + 1 == assets.end.len() && !times.is_empty() {
+                for (n, time) in times.iter().enumerate() {
+                    lines.push(format!("Level {}: {time:.1}s", n + 1));
+                }
+                lines.push(format!("Total: {:.1}s", times.iter().sum::<f32>()));
+            }
+            let start = 0.5 - 0.04 * lines.len() as f32;
+            for (n, line) in lines.iter().enumerate() {
                 draw_centered_txt(screen, line, start + 0.08 * (n + 1) as f32, 0.045, WHITE);
             }
         }
     }
+    if paused {
+        draw_rect(
+            screen,
+            0.,
+            0.,
+            RATIO_W_H,
+            1.,
+            Color::from_rgba(0, 0, 0, 160),
+        );
+        draw_centered_txt(screen, "Paused", 0.5, 0.1, WHITE);
+    }
 
     draw_cursor(state, assets, screen);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_ducks_the_music_and_resume_restores_it() {
+        let mut settings = Settings::default();
+        let mut pause = Pause::default();
+        assert_eq!(pause.music_volume(&settings), settings.music_volume());
+        let ducked = pause.toggle(&settings);
+        assert!(pause.paused());
+        assert_eq!(ducked, settings.music_volume() * PAUSED_MUSIC);
+        // The volume changed while paused is the one the game resumes with
+        settings.change_volume(-VOLUME_STEP);
+        assert_eq!(
+            pause.music_volume(&settings),
+            settings.music_volume() * PAUSED_MUSIC
+        );
+        let resumed = pause.toggle(&settings);
+        assert!(!pause.paused());
+        assert_eq!(resumed, settings.music_volume());
+    }
+}
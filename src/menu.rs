@@ -0,0 +1,129 @@
+use macroquad::prelude::*;
+
+use crate::graphics::{draw_centered_txt, Screen};
+
+/// Height of a menu row
+pub const MENU_ROW: f32 = 0.1;
+pub const MENU_FONT: f32 = 0.06;
+
+/// List of actions chosen with up/down and Enter or with the mouse
+#[derive(Clone)]
+pub struct Menu<T> {
+    /// Label, action and whether it can be chosen
+    pub items: Vec<(String, T, bool)>,
+    pub focused: usize,
+    /// Where the first row is drawn
+    pub top: f32,
+    /// Mouse position seen last frame, so a still mouse doesn't take the focus
+    mouse: Option<Vec2>,
+}
+
+impl<T: Copy> Menu<T> {
+    pub fn new(items: Vec<(String, T, bool)>, top: f32) -> Self {
+        let focused = items.iter().position(|item| item.2).unwrap_or(0);
+        Self {
+            items,
+            focused,
+            top,
+            mouse: None,
+        }
+    }
+
+    /// Moves the focus by the step, skipping disabled rows and wrapping around
+    pub fn step(&mut self, step: isize) {
+        let len = self.items.len() as isize;
+        for n in 1..=len {
+            let next = (self.focused as isize + step * n).rem_euclid(len) as usize;
+            if self.items[next].2 {
+                self.focused = next;
+                return;
+            }
+        }
+    }
+
+    fn row_y(&self, n: usize) -> f32 {
+        self.top + MENU_ROW * n as f32
+    }
+
+    /// Enabled row under the world y
+    fn row_at(&self, y: f32) -> Option<usize> {
+        // Text is drawn above its y, so rows are checked from the baseline up
+        (0..self.items.len()).find(|&n| {
+            let row = self.row_y(n);
+            self.items[n].2 && y > row - MENU_ROW * 0.75 && y <= row + MENU_ROW * 0.25
+        })
+    }
+
+    /// Chosen action, if any
+    pub fn update(&mut self, screen: &Screen) -> Option<T> {
+        if is_key_pressed(KeyCode::Up) || is_key_pressed(KeyCode::W) {
+            self.step(-1);
+        }
+        if is_key_pressed(KeyCode::Down) || is_key_pressed(KeyCode::S) {
+            self.step(1);
+        }
+        let position: Vec2 = mouse_position().into();
+        let moved = self.mouse.is_some_and(|last| last != position);
+        self.mouse = Some(position);
+        let clicked = is_mouse_button_pressed(MouseButton::Left);
+        let hovered = self.row_at(screen.screen_to_world(position).y);
+        if let Some(n) = hovered.filter(|_| moved || clicked) {
+            self.focused = n;
+        }
+        let activate = is_key_pressed(KeyCode::Enter)
+            || is_key_pressed(KeyCode::Space)
+            || (hovered.is_some() && clicked);
+        let (_, action, enabled) = &self.items[self.focused];
+        (activate && *enabled).then_some(*action)
+    }
+
+    pub fn draw(&self, screen: &Screen) {
+        for (n, (label, _, enabled)) in self.items.iter().enumerate() {
+            let color = if !enabled {
+                GRAY
+            } else if n == self.focused {
+                YELLOW
+            } else {
+                WHITE
+            };
+            draw_centered_txt(screen, label, self.row_y(n), MENU_FONT, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn menu() -> Menu<u8> {
+        Menu::new(
+            vec![
+                ("A".to_owned(), 0, true),
+                ("B".to_owned(), 1, false),
+                ("C".to_owned(), 2, true),
+            ],
+            0.5,
+        )
+    }
+
+    #[test]
+    fn step_skips_disabled_rows_and_wraps() {
+        let mut menu = menu();
+        assert_eq!(menu.focused, 0);
+        menu.step(1);
+        assert_eq!(menu.focused, 2);
+        menu.step(1);
+        assert_eq!(menu.focused, 0);
+        menu.step(-1);
+        assert_eq!(menu.focused, 2);
+    }
+
+    #[test]
+    fn row_at_finds_enabled_rows_only() {
+        let menu = menu();
+        assert_eq!(menu.row_at(0.5), Some(0));
+        assert_eq!(menu.row_at(0.6), None);
+        assert_eq!(menu.row_at(0.7), Some(2));
+        assert_eq!(menu.row_at(0.2), None);
+    }
+}
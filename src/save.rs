@@ -0,0 +1,136 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{settings::Difficulty, State};
+
+const SAVE_FILE: &str = "progress.json";
+
+/// Part of the run that is saved, levels hold too much runtime data to be saved whole
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SaveData {
+    pub level: usize,
+    /// Whether the chapter's battle has started, its scene is shown otherwise
+    #[serde(default)]
+    pub battle: bool,
+    #[serde(default)]
+    pub difficulty: Difficulty,
+    /// Index of the campaign played, saves from before campaigns are of the first one
+    #[serde(default)]
+    pub campaign: usize,
+    /// Seconds spent in each finished level
+    #[serde(default)]
+    pub times: Vec<f32>,
+}
+
+impl SaveData {
+    fn new(state: &State, difficulty: Difficulty, campaign: usize) -> Self {
+        let (level, battle, times) = match state {
+            State::Scene(num, _, times) => (*num, false, times.clone()),
+            State::Battle(num, _, times) => (*num, true, times.clone()),
+            // The next run starts over
+            State::Menu(_) | State::End(_, _) => (0, false, Vec::new()),
+        };
+        Self {
+            level,
+            battle,
+            difficulty,
+            campaign,
+            times,
+        }
+    }
+}
+
+/// Folder for the game data in the usual place of the platform
+fn save_dir() -> Option<PathBuf> {
+    let home = || std::env::var_os("HOME").map(PathBuf::from);
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home().map(|home| home.join(".local/share")))
+    };
+    base.map(|base| base.join("cooking_thief"))
+}
+
+/// Where the progress is saved, `None` if the platform has no place for it
+pub fn save_path() -> Option<PathBuf> {
+    save_dir().map(|dir| dir.join(SAVE_FILE))
+}
+
+pub fn save(state: &State, difficulty: Difficulty, campaign: usize, path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, to_text(&SaveData::new(state, difficulty, campaign))?)
+}
+
+pub fn load(path: &Path) -> io::Result<SaveData> {
+    from_text(&fs::read_to_string(path)?)
+}
+
+fn to_text(data: &SaveData) -> io::Result<String> {
+    serde_json::to_string(data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn from_text(text: &str) -> io::Result<SaveData> {
+    serde_json::from_str(text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_data_round_trips() {
+        let data = SaveData {
+            level: 2,
+            battle: true,
+            difficulty: Difficulty::Hard,
+            campaign: 1,
+            times: vec![12.5, 40.25],
+        };
+        assert_eq!(from_text(&to_text(&data).unwrap()).unwrap(), data);
+    }
+
+    #[test]
+    fn run_is_saved_as_json() {
+        let data = SaveData {
+            level: 1,
+            battle: false,
+            difficulty: Difficulty::Easy,
+            campaign: 0,
+            times: vec![30.5],
+        };
+        assert_eq!(
+            to_text(&data).unwrap(),
+            r#"{"level":1,"battle":false,"difficulty":"Easy","campaign":0,"times":[30.5]}"#
+        );
+        // Saves with only the level start the rest fresh
+        let data = from_text(r#"{"level": 3}"#).unwrap();
+        assert!(!data.battle);
+        assert_eq!(data.difficulty, Difficulty::Normal);
+        assert!(data.times.is_empty());
+    }
+
+    #[test]
+    fn old_save_is_of_the_first_campaign() {
+        assert_eq!(
+            from_text(r#"{"level": 2, "battle": true}"#)
+                .unwrap()
+                .campaign,
+            0
+        );
+    }
+
+    #[test]
+    fn broken_save_is_an_error() {
+        assert!(from_text(r#"{"level": ["#).is_err());
+    }
+}
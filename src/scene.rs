@@ -1,5 +1,8 @@
 use macroquad::{
-    prelude::{is_key_pressed, is_mouse_button_pressed, Color, KeyCode, MouseButton, Vec2, WHITE},
+    prelude::{
+        is_key_down, is_key_pressed, is_mouse_button_pressed, Color, KeyCode, MouseButton, Vec2,
+        WHITE,
+    },
     texture::{draw_texture_ex, DrawTextureParams},
 };
 use serde::Deserialize;
@@ -7,10 +10,24 @@ use serde::Deserialize;
 use crate::{
     assets::Assets,
     graphics::{draw_rect, draw_txt, get_lines, Screen},
+    level::Outcome,
+    menu::{Menu, MENU_ROW},
     RATIO_W_H,
 };
 
 pub const LETTERS_PER_SECOND: f32 = 30.0;
+/// Slowest typing speed, so the text never stalls
+pub const MIN_LETTERS_PER_SECOND: f32 = 1.0;
+/// Baseline of the first line of a card's text
+pub const TEXT_TOP: f32 = 0.65;
+/// Lowest baseline of the text and the choices of a card
+pub const TEXT_BOTTOM: f32 = 0.95;
+pub const TEXT_FONT: f32 = 0.075;
+pub const LINE_HEIGHT: f32 = 0.1;
+/// Seconds a printed card stays when auto-advance is turned on in the menu
+pub const AUTO_ADVANCE_TIME: f32 = 3.0;
+/// Seconds to hold the key that skips the whole scene
+pub const SKIP_HOLD_TIME: f32 = 1.0;
 
 #[derive(Clone)]
 pub enum State {
@@ -29,6 +46,123 @@ pub struct Scene {
     #[serde(skip)]
     pub current: usize,
     pub background: String,
+    /// Letters typed per second, `LETTERS_PER_SECOND` if not set
+    pub speed: Option<f32>,
+    #[serde(skip)]
+    pub outcome: Outcome,
+    /// Seconds the key that skips the whole scene has been held
+    #[serde(skip)]
+    pub skip_hold: f32,
+}
+
+impl Scene {
+    /// Prepares the scene to be shown after the battle ended with the outcome
+    pub fn start(&mut self, outcome: Outcome) {
+        self.outcome = outcome;
+        self.current = self.next_card(None).unwrap_or(0);
+        if let Some(card) = self.cards.get_mut(self.current) {
+            card.reset();
+        }
+    }
+    /// Keys of all images the scene shows
+    pub fn images(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.background.as_str()).chain(self.cards.iter().flat_map(|card| {
+            card.background
+                .iter()
+                .chain(card.image.iter())
+                .map(String::as_str)
+        }))
+    }
+    /// Typing speed of the current card, the card's own speed comes first
+    pub fn letters_per_second(&self) -> f32 {
+        self.cards[self.current]
+            .speed
+            .or(self.speed)
+            .map_or(LETTERS_PER_SECOND, |speed| {
+                speed.max(MIN_LETTERS_PER_SECOND)
+            })
+    }
+    fn next_card(&self, from: Option<usize>) -> Option<usize> {
+        let start = match from.map(|from| (from, self.cards[from].next)) {
+            None => 0,
+            Some((from, None)) => from + 1,
+            Some((_, Some(Next::Card(target)))) => target,
+            Some((_, Some(Next::End))) => return None,
+        };
+        (start..self.cards.len()).find(|&n| self.cards[n].shown(&self.outcome))
+    }
+    /// Last card to show, or the next card with choices as they can't be skipped
+    fn skip_target(&self) -> usize {
+        let mut target = self.current;
+        while self.cards[target].choices.is_none() {
+            match self.next_card(Some(target)) {
+                Some(next) => target = next,
+                None => break,
+            }
+        }
+        target
+    }
+    /// Card with a choice leading past the last card, or a jump that doesn't lead forward
+    /// to a card, and the target
+    pub fn bad_target(&self) -> Option<(usize, usize)> {
+        self.cards.iter().enumerate().find_map(|(n, card)| {
+            let jump = match card.next {
+                Some(Next::Card(target)) if target <= n || target >= self.cards.len() => {
+                    Some(target)
+                }
+                _ => None,
+            };
+            card.choices
+                .iter()
+                .flatten()
+                .map(|(_, target)| *target)
+                .find(|target| *target >= self.cards.len())
+                .or(jump)
+                .map(|target| (n, target))
+        })
+    }
+    /// Goes to the previous shown card, the first card stays
+    pub fn back(&mut self) {
+        if let Some(prev) = self.prev_card(self.current) {
+            self.current = prev;
+            // Type the card again as when it was first shown
+            self.cards[prev].reset();
+        }
+    }
+    fn prev_card(&self, from: usize) -> Option<usize> {
+        (0..from)
+            .rev()
+            .find(|&n| self.cards[n].shown(&self.outcome))
+    }
+}
+
+/// Condition on the previous battle for the card to be shown
+#[derive(Deserialize, Clone, Copy)]
+pub enum Condition {
+    Spotted(bool),
+    Died(bool),
+    Pacifist(bool),
+    /// At least that many items were taken out of the level
+    Loot(u8),
+}
+
+impl Condition {
+    pub fn holds(self, outcome: &Outcome) -> bool {
+        match self {
+            Self::Spotted(spotted) => outcome.spotted == spotted,
+            Self::Died(died) => outcome.died == died,
+            Self::Pacifist(pacifist) => outcome.pacifist == pacifist,
+            Self::Loot(loot) => outcome.loot >= loot,
+        }
+    }
+}
+
+/// Where a card leads instead of the card after it
+#[derive(Deserialize, Clone, Copy)]
+pub enum Next {
+    Card(usize),
+    /// The scene ends after the card
+    End,
 }
 
 #[derive(Deserialize, Clone)]
@@ -37,11 +171,38 @@ pub struct Card {
     #[serde(skip)]
     pub state: State,
     pub image: Option<String>,
+    /// Replaces the background of the scene while the card is shown
+    pub background: Option<String>,
+    pub condition: Option<Condition>,
+    /// Letters typed per second, the speed of the scene if not set
+    pub speed: Option<f32>,
+    /// Seconds the whole text has been shown
+    #[serde(skip)]
+    pub view_time: f32,
+    /// Options shown after the text and the cards they lead to
+    pub choices: Option<Vec<(String, usize)>>,
+    /// Where the card leads instead of the following card, so that the last card of a
+    /// branch skips the other branches
+    pub next: Option<Next>,
+    #[serde(skip)]
+    pub menu: Option<Menu<usize>>,
 }
 
 impl Card {
+    pub fn shown(&self, outcome: &Outcome) -> bool {
+        self.condition
+            .is_none_or(|condition| condition.holds(outcome))
+    }
     pub fn reset(&mut self) {
         self.state = State::Printing(0.0);
+        self.view_time = 0.;
+        self.menu = self.choices.as_ref().map(|choices| {
+            let items = choices
+                .iter()
+                .map(|(text, target)| (text.clone(), *target, true))
+                .collect();
+            Menu::new(items, TEXT_BOTTOM)
+        });
     }
     pub fn skip(&mut self) -> bool {
         match self.state {
@@ -54,39 +215,96 @@ impl Card {
     }
 }
 
-pub fn update_scene(scene: &mut Scene, dt: f32) -> bool {
+/// Baselines of the first line of text and of the first choice for a card with that
+/// many lines and choices. Long cards move up so the choices stay on the screen
+pub fn card_layout(lines: usize, choices: usize) -> (f32, f32) {
+    let text_height = LINE_HEIGHT * lines.saturating_sub(1) as f32;
+    let top = TEXT_TOP.min(TEXT_BOTTOM - text_height - MENU_ROW * choices as f32);
+    (top, top + text_height + MENU_ROW)
+}
+
+/// Lines the whole text of the card takes on the screen
+fn card_lines<'a>(screen: &Screen, card: &'a Card) -> Vec<&'a str> {
+    get_lines(screen, RATIO_W_H - 0.2, TEXT_FONT, &card.text).0
+}
+
+/// `auto_advance` is the time a printed card stays before the next one, `None` waits for
+/// a key
+pub fn update_scene(
+    scene: &mut Scene,
+    screen: &Screen,
+    auto_advance: Option<f32>,
+    dt: f32,
+) -> bool {
+    if is_key_down(KeyCode::Backspace) {
+        scene.skip_hold += dt;
+        if scene.skip_hold >= SKIP_HOLD_TIME {
+            scene.skip_hold = 0.;
+            return true;
+        }
+    } else {
+        scene.skip_hold = 0.;
+    }
     let current = scene.current;
+    let speed = scene.letters_per_second();
     let card = scene.cards.get_mut(current).unwrap();
-    if let crate::scene::State::Printing(letters) = &mut card.state {
-        *letters += dt * LETTERS_PER_SECOND;
-        if *letters > card.text.len() as f32 {
-            card.state = crate::scene::State::View;
+    let lines = card_lines(screen, card).len();
+    if let Some(menu) = &mut card.menu {
+        menu.top = card_layout(lines, menu.items.len()).1;
+    }
+    // Choices wait for the whole text to be shown
+    if let (Some(menu), State::View) = (&mut card.menu, &card.state) {
+        if let Some(target) = menu.update(screen) {
+            if target < scene.cards.len() {
+                scene.current = target;
+                scene.cards[target].reset();
+            }
+            return false;
+        }
+    }
+    match &mut card.state {
+        crate::scene::State::Printing(letters) => {
+            *letters += dt * speed;
+            if *letters > card.text.len() as f32 {
+                card.state = crate::scene::State::View;
+            }
         }
+        crate::scene::State::View => card.view_time += dt,
     }
+    if is_key_pressed(KeyCode::Tab) {
+        let target = scene.skip_target();
+        if target != current {
+            scene.current = target;
+            scene.cards[target].reset();
+        }
+        scene.cards[target].state = State::View;
+        return false;
+    }
+    let card = &mut scene.cards[current];
     let forward = is_key_pressed(KeyCode::Space)
         || is_key_pressed(KeyCode::Enter)
         || is_key_pressed(KeyCode::D)
         || is_key_pressed(KeyCode::Right)
         || is_mouse_button_pressed(MouseButton::Left);
-    if forward && card.skip() {
-        scene.current += 1;
-
-        scene.cards.get_mut(current + 1).map(Card::reset);
-
-        if scene.current >= scene.cards.len() {
-            scene.current -= 1;
+    let advance = auto_advance
+        .is_some_and(|delay| matches!(card.state, State::View) && card.view_time >= delay);
+    if (advance || forward && card.skip()) && card.menu.is_none() {
+        let Some(next) = scene.next_card(Some(current)) else {
             return true;
-        }
+        };
+        scene.current = next;
+        scene.cards[next].reset();
     }
     if is_key_pressed(KeyCode::A) || is_key_pressed(KeyCode::Left) {
-        scene.current = scene.current.saturating_sub(1);
+        scene.back();
     }
     false
 }
 
 pub fn draw_scene(scene: &Scene, assets: &Assets, screen: &Screen) {
+    let card = &scene.cards[scene.current];
     draw_texture_ex(
-        assets.images[&scene.background],
+        assets.images[card.background.as_ref().unwrap_or(&scene.background)],
         screen.x,
         screen.y,
         WHITE,
@@ -98,7 +316,6 @@ pub fn draw_scene(scene: &Scene, assets: &Assets, screen: &Screen) {
             ..Default::default()
         },
     );
-    let card = &scene.cards[scene.current];
     let text = match card.state {
         crate::scene::State::Printing(letters) => &card.text[0..(letters.floor() as usize)],
         crate::scene::State::View => &card.text,
@@ -120,16 +337,220 @@ pub fn draw_scene(scene: &Scene, assets: &Assets, screen: &Screen) {
             },
         );
     }
+    // The layout follows the whole text, so it doesn't move while typing
+    let choices = card.menu.as_ref().map_or(0, |menu| menu.items.len());
+    let (top, _) = card_layout(card_lines(screen, card).len(), choices);
     draw_rect(
         &screen,
         0.05,
-        0.55,
+        top - LINE_HEIGHT,
         RATIO_W_H - 0.1,
-        0.4,
+        TEXT_BOTTOM - top + LINE_HEIGHT,
         Color::from_rgba(0, 0, 0, 128),
     );
-    let (lines, _) = get_lines(&screen, RATIO_W_H - 0.2, 0.075, text);
+    let (lines, _) = get_lines(&screen, RATIO_W_H - 0.2, TEXT_FONT, text);
     for (n, line) in lines.into_iter().enumerate() {
-        draw_txt(&screen, line, 0.1, 0.65 + (0.1 * n as f32), 0.075, WHITE);
+        draw_txt(
+            &screen,
+            line,
+            0.1,
+            top + (LINE_HEIGHT * n as f32),
+            TEXT_FONT,
+            WHITE,
+        );
+    }
+    if let (Some(menu), State::View) = (&card.menu, &card.state) {
+        menu.draw(screen);
+    }
+    if scene.skip_hold > 0. {
+        let progress = scene.skip_hold / SKIP_HOLD_TIME;
+        draw_txt(screen, "Skipping...", 0.05, 0.05, 0.04, WHITE);
+        draw_rect(
+            screen,
+            0.05,
+            0.07,
+            0.3,
+            0.01,
+            Color::from_rgba(0, 0, 0, 128),
+        );
+        draw_rect(screen, 0.05, 0.07, 0.3 * progress, 0.01, WHITE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene(yaml: &str) -> Scene {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Texts of the cards the scene goes through for the outcome
+    fn shown(scene: &mut Scene, outcome: Outcome) -> Vec<String> {
+        scene.start(outcome);
+        follow(scene)
+    }
+
+    /// Texts of the cards from the current one to the end
+    fn follow(scene: &mut Scene) -> Vec<String> {
+        let mut texts = vec![scene.cards[scene.current].text.clone()];
+        while let Some(next) = scene.next_card(Some(scene.current)) {
+            scene.current = next;
+            texts.push(scene.cards[next].text.clone());
+        }
+        texts
+    }
+
+    #[test]
+    fn conditions_check_the_outcome() {
+        let outcome = Outcome {
+            spotted: true,
+            died: false,
+            pacifist: true,
+            loot: 2,
+        };
+        assert!(Condition::Spotted(true).holds(&outcome));
+        assert!(!Condition::Spotted(false).holds(&outcome));
+        assert!(Condition::Died(false).holds(&outcome));
+        assert!(!Condition::Died(true).holds(&outcome));
+        assert!(Condition::Pacifist(true).holds(&outcome));
+        assert!(!Condition::Pacifist(false).holds(&outcome));
+        assert!(Condition::Loot(0).holds(&outcome));
+        assert!(Condition::Loot(2).holds(&outcome));
+        assert!(!Condition::Loot(3).holds(&outcome));
+    }
+
+    #[test]
+    fn spotted_card_is_skipped_after_a_ghost_run() {
+        let mut scene = scene(
+            "
+background: village
+cards:
+- text: They saw you
+  condition: !Spotted true
+- text: Nobody saw you
+  condition: !Spotted false
+- text: Morning came
+",
+        );
+        let ghost = Outcome {
+            pacifist: true,
+            ..Default::default()
+        };
+        assert_eq!(shown(&mut scene, ghost), ["Nobody saw you", "Morning came"]);
+        let spotted = Outcome {
+            spotted: true,
+            ..Default::default()
+        };
+        assert_eq!(shown(&mut scene, spotted), ["They saw you", "Morning came"]);
+    }
+
+    #[test]
+    fn choices_are_laid_out_below_the_text() {
+        for lines in 1..6 {
+            for choices in 1..4 {
+                let (top, choices_top) = card_layout(lines, choices);
+                let last_line = top + LINE_HEIGHT * (lines - 1) as f32;
+                assert!(choices_top >= last_line + MENU_ROW - 1e-6);
+                let last_choice = choices_top + MENU_ROW * (choices - 1) as f32;
+                assert!(last_choice <= TEXT_BOTTOM + 1e-6, "{lines} {choices}");
+                assert!(top <= TEXT_TOP);
+            }
+        }
+        // Short cards keep the text where it always was
+        assert_eq!(card_layout(1, 2).0, TEXT_TOP);
+    }
+
+    #[test]
+    fn choice_past_the_last_card_is_found() {
+        let yaml = "
+background: village
+cards:
+- text: Where to?
+  choices:
+  - [Left, 1]
+  - [Right, 2]
+- text: Left
+";
+        assert_eq!(scene(yaml).bad_target(), Some((0, 2)));
+        let fixed = yaml.to_owned() + "- text: Right\n";
+        assert_eq!(scene(&fixed).bad_target(), None);
+        // Jumps only lead forward
+        let back = fixed.replace("- text: Left\n", "- text: Left\n  next: !Card 0\n");
+        assert_eq!(scene(&back).bad_target(), Some((1, 0)));
+        let past = fixed.replace("- text: Left\n", "- text: Left\n  next: !Card 3\n");
+        assert_eq!(scene(&past).bad_target(), Some((1, 3)));
+    }
+
+    /// Texts of the cards shown after picking the choice of the first card
+    fn branch(scene: &mut Scene, choice: usize) -> Vec<String> {
+        scene.start(Outcome::default());
+        scene.current = scene.cards[0].choices.as_ref().unwrap()[choice].1;
+        follow(scene)
+    }
+
+    #[test]
+    fn branch_skips_the_other_branches() {
+        let mut scene = scene(
+            "
+background: village
+cards:
+- text: Where to?
+  choices:
+  - [Market, 1]
+  - [Docks, 3]
+  - [Home, 5]
+- text: The market is loud
+- text: You buy bread
+  next: !Card 6
+- text: The docks are quiet
+- text: You watch the ships
+  next: !Card 6
+- text: You go to bed
+  next: End
+- text: Evening came
+",
+        );
+        assert!(scene.bad_target().is_none());
+        assert_eq!(
+            branch(&mut scene, 0),
+            ["The market is loud", "You buy bread", "Evening came"]
+        );
+        assert_eq!(
+            branch(&mut scene, 1),
+            ["The docks are quiet", "You watch the ships", "Evening came"]
+        );
+        assert_eq!(branch(&mut scene, 2), ["You go to bed"]);
+    }
+
+    #[test]
+    fn going_back_types_the_previous_card_again() {
+        let mut scene = scene(
+            "
+background: village
+cards:
+- text: First
+- text: Second
+- text: Third
+",
+        );
+        scene.start(Outcome::default());
+        for card in &mut scene.cards {
+            card.state = State::View;
+            card.view_time = 2.;
+        }
+        scene.current = 2;
+        scene.back();
+        assert_eq!(scene.current, 1);
+        assert!(matches!(scene.cards[1].state, State::Printing(letters) if letters == 0.));
+        assert_eq!(scene.cards[1].view_time, 0.);
+        scene.back();
+        assert_eq!(scene.current, 0);
+        assert!(matches!(scene.cards[0].state, State::Printing(letters) if letters == 0.));
+        // The first card has nothing before it
+        scene.cards[0].state = State::View;
+        scene.back();
+        assert_eq!(scene.current, 0);
+        assert!(matches!(scene.cards[0].state, State::View));
     }
 }
@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+
+use macroquad::{
+    logging::warn,
+    prelude::{clamp, KeyCode, MouseButton},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    level::{ENEMY_FOV, HEAL_TIME, PLAYER_RELOAD},
+    RATIO_W_H,
+};
+
+pub const MIN_TIME_SCALE: f32 = 0.5;
+pub const MAX_TIME_SCALE: f32 = 1.5;
+/// Game speed change for one press in the settings menu
+pub const TIME_SCALE_STEP: f32 = 0.25;
+/// Master volume change for one key press
+pub const VOLUME_STEP: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+/// Guard tuning of a difficulty
+pub struct DifficultyParams {
+    /// Seconds between melee attacks of a guard
+    pub enemy_reload: f32,
+    /// Seconds between throws of a guard
+    pub enemy_throw_reload: f32,
+    /// Multiplier of how far guards see and notice the player next to them
+    pub detection: f32,
+    /// Guards added to every guarded room
+    pub extra_guards: u8,
+}
+
+impl Difficulty {
+    pub const ALL: [Self; 3] = [Self::Easy, Self::Normal, Self::Hard];
+
+    pub fn params(self) -> DifficultyParams {
+        match self {
+            Self::Easy => DifficultyParams {
+                enemy_reload: 1.5 * PLAYER_RELOAD,
+                enemy_throw_reload: 2.,
+                detection: 0.85,
+                extra_guards: 0,
+            },
+            Self::Normal => DifficultyParams {
+                enemy_reload: PLAYER_RELOAD,
+                enemy_throw_reload: 1.5,
+                detection: 1.,
+                extra_guards: 0,
+            },
+            Self::Hard => DifficultyParams {
+                enemy_reload: 0.75 * PLAYER_RELOAD,
+                enemy_throw_reload: 1.,
+                detection: 1.15,
+                extra_guards: 1,
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Easy => "Easy",
+            Self::Normal => "Normal",
+            Self::Hard => "Hard",
+        }
+    }
+}
+
+pub struct Heal {
+    /// Whether the player heals from low health at all
+    pub enabled: bool,
+    /// Seconds with low health before healing
+    pub time: f32,
+    /// Pause healing while any guard is fighting
+    pub out_of_combat: bool,
+}
+
+impl Default for Heal {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            time: HEAL_TIME,
+            out_of_combat: false,
+        }
+    }
+}
+
+/// Keys that can be bound in the config
+const KEYS: [KeyCode; 92] = [
+    KeyCode::Space,
+    KeyCode::Apostrophe,
+    KeyCode::Comma,
+    KeyCode::Minus,
+    KeyCode::Period,
+    KeyCode::Slash,
+    KeyCode::Key0,
+    KeyCode::Key1,
+    KeyCode::Key2,
+    KeyCode::Key3,
+    KeyCode::Key4,
+    KeyCode::Key5,
+    KeyCode::Key6,
+    KeyCode::Key7,
+    KeyCode::Key8,
+    KeyCode::Key9,
+    KeyCode::Semicolon,
+    KeyCode::Equal,
+    KeyCode::A,
+    KeyCode::B,
+    KeyCode::C,
+    KeyCode::D,
+    KeyCode::E,
+    KeyCode::F,
+    KeyCode::G,
+    KeyCode::H,
+    KeyCode::I,
+    KeyCode::J,
+    KeyCode::K,
+    KeyCode::L,
+    KeyCode::M,
+    KeyCode::N,
+    KeyCode::O,
+    KeyCode::P,
+    KeyCode::Q,
+    KeyCode::R,
+    KeyCode::S,
+    KeyCode::T,
+    KeyCode::U,
+    KeyCode::V,
+    KeyCode::W,
+    KeyCode::X,
+    KeyCode::Y,
+    KeyCode::Z,
+    KeyCode::LeftBracket,
+    KeyCode::Backslash,
+    KeyCode::RightBracket,
+    KeyCode::GraveAccent,
+    KeyCode::Escape,
+    KeyCode::Enter,
+    KeyCode::Tab,
+    KeyCode::Backspace,
+    KeyCode::Insert,
+    KeyCode::Delete,
+    KeyCode::Right,
+    KeyCode::Left,
+    KeyCode::Down,
+    KeyCode::Up,
+    KeyCode::PageUp,
+    KeyCode::PageDown,
+    KeyCode::Home,
+    KeyCode::End,
+    KeyCode::CapsLock,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::Kp0,
+    KeyCode::Kp1,
+    KeyCode::Kp2,
+    KeyCode::Kp3,
+    KeyCode::Kp4,
+    KeyCode::Kp5,
+    KeyCode::Kp6,
+    KeyCode::Kp7,
+    KeyCode::Kp8,
+    KeyCode::Kp9,
+    KeyCode::KpEnter,
+    KeyCode::LeftShift,
+    KeyCode::LeftControl,
+    KeyCode::LeftAlt,
+    KeyCode::RightShift,
+    KeyCode::RightControl,
+    KeyCode::RightAlt,
+];
+
+pub struct KeyBindings {
+    /// Movement, any of the keys works
+    pub up: Vec<KeyCode>,
+    pub down: Vec<KeyCode>,
+    pub left: Vec<KeyCode>,
+    pub right: Vec<KeyCode>,
+    pub crouch: KeyCode,
+    /// Hold to run faster while stamina lasts
+    pub sprint: KeyCode,
+    /// Swap items with a crate
+    pub interact: KeyCode,
+    /// Put the item in hand down as a new crate
+    pub drop: KeyCode,
+    pub attack: MouseButton,
+    /// Continue from the last save after death
+    pub restart: KeyCode,
+    /// Start the level over at any time
+    pub restart_level: KeyCode,
+    pub zoom_in: KeyCode,
+    pub zoom_out: KeyCode,
+    /// Hold at a door to look into the next room
+    pub peek: KeyCode,
+    /// Works together with Escape
+    pub pause: KeyCode,
+    pub volume_down: KeyCode,
+    pub volume_up: KeyCode,
+    /// Shows or hides the map of the rooms
+    pub minimap: KeyCode,
+    /// Shows or hides the captions of the phrases
+    pub captions: KeyCode,
+    /// Skips the intro of a level, any of the keys works
+    pub skip: Vec<KeyCode>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            up: vec![KeyCode::W, KeyCode::Up],
+            down: vec![KeyCode::S, KeyCode::Down],
+            left: vec![KeyCode::A, KeyCode::Left],
+            right: vec![KeyCode::D, KeyCode::Right],
+            crouch: KeyCode::Space,
+            sprint: KeyCode::LeftShift,
+            interact: KeyCode::E,
+            drop: KeyCode::G,
+            attack: MouseButton::Left,
+            restart: KeyCode::R,
+            restart_level: KeyCode::Backspace,
+            zoom_in: KeyCode::Equal,
+            zoom_out: KeyCode::Minus,
+            peek: KeyCode::Q,
+            pause: KeyCode::P,
+            volume_down: KeyCode::LeftBracket,
+            volume_up: KeyCode::RightBracket,
+            minimap: KeyCode::Tab,
+            captions: KeyCode::C,
+            skip: vec![KeyCode::Space, KeyCode::Enter],
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Defaults changed by the YAML config mapping actions to key names,
+    /// e.g. `up: [E]`, `left: [S]`, `down: [D]`, `right: [F]` and `interact: [G]`, `drop: [T]` for ESDF
+    pub fn load(config: Option<&str>) -> Self {
+        let mut keys = Self::default();
+        let Some(config) = config else {
+            return keys;
+        };
+        let config: HashMap<String, Vec<String>> = match serde_yaml::from_str(config) {
+            Ok(config) => config,
+            Err(err) => {
+                warn!("Key bindings are broken, using defaults: {err}");
+                return keys;
+            }
+        };
+        for (action, names) in config {
+            if action == "attack" {
+                match names.first().map(String::as_str) {
+                    Some("Left") => keys.attack = MouseButton::Left,
+                    Some("Right") => keys.attack = MouseButton::Right,
+                    Some("Middle") => keys.attack = MouseButton::Middle,
+                    _ => warn!("Unknown mouse button for attack: {names:?}"),
+                }
+                continue;
+            }
+            let codes: Vec<_> = names
+                .iter()
+                .filter_map(|name| {
+                    let code = KEYS.into_iter().find(|key| key_name(*key) == *name);
+                    if code.is_none() {
+                        warn!("Unknown key {name} for {action}");
+                    }
+                    code
+                })
+                .collect();
+            let Some(&first) = codes.first() else {
+                continue;
+            };
+            match action.as_str() {
+                "up" => keys.up = codes,
+                "down" => keys.down = codes,
+                "left" => keys.left = codes,
+                "right" => keys.right = codes,
+                "skip" => keys.skip = codes,
+                "crouch" => keys.crouch = first,
+                "sprint" => keys.sprint = first,
+                "interact" => keys.interact = first,
+                "drop" => keys.drop = first,
+                "restart" => keys.restart = first,
+                "restart_level" => keys.restart_level = first,
+                "zoom_in" => keys.zoom_in = first,
+                "zoom_out" => keys.zoom_out = first,
+                "peek" => keys.peek = first,
+                "pause" => keys.pause = first,
+                "volume_down" => keys.volume_down = first,
+                "volume_up" => keys.volume_up = first,
+                "minimap" => keys.minimap = first,
+                "captions" => keys.captions = first,
+                _ => warn!("Unknown action {action} in key bindings"),
+            }
+        }
+        for (key, first, second) in keys.conflicts() {
+            warn!("{} is bound to both {first} and {second}", key_name(key));
+        }
+        keys
+    }
+
+    /// Movement for the held keys, `is_down` tells whether a key is held.
+    /// Opposite directions cancel each other out
+    pub fn move_direction(&self, is_down: impl Fn(KeyCode) -> bool) -> (i32, i32) {
+        let held = |keys: &[KeyCode]| i32::from(keys.iter().any(|key| is_down(*key)));
+        (
+            held(&self.right) - held(&self.left),
+            held(&self.down) - held(&self.up),
+        )
+    }
+
+    /// Keys bound to several actions. Skip keys only work while nothing else does, so they may overlap
+    pub fn conflicts(&self) -> Vec<(KeyCode, &'static str, &'static str)> {
+        let mut bound: Vec<(KeyCode, &'static str)> = Vec::new();
+        for (action, keys) in [
+            ("up", &self.up),
+            ("down", &self.down),
+            ("left", &self.left),
+            ("right", &self.right),
+        ] {
+            bound.extend(keys.iter().map(|key| (*key, action)));
+        }
+        bound.extend([
+            (self.crouch, "crouch"),
+            (self.sprint, "sprint"),
+            (self.interact, "interact"),
+            (self.drop, "drop"),
+            (self.restart, "restart"),
+            (self.restart_level, "restart_level"),
+            (self.zoom_in, "zoom_in"),
+            (self.zoom_out, "zoom_out"),
+            (self.peek, "peek"),
+            (self.pause, "pause"),
+            (self.volume_down, "volume_down"),
+            (self.volume_up, "volume_up"),
+            (self.minimap, "minimap"),
+            (self.captions, "captions"),
+        ]);
+        let mut conflicts = Vec::new();
+        for (n, (key, action)) in bound.iter().enumerate() {
+            if let Some((_, other)) = bound[..n].iter().find(|(other, _)| other == key) {
+                conflicts.push((*key, *other, *action));
+            }
+        }
+        conflicts
+    }
+}
+
+/// Name of the key to show in hints
+pub fn key_name(key: KeyCode) -> String {
+    format!("{key:?}")
+}
+
+pub struct Settings {
+    pub keys: KeyBindings,
+    pub difficulty: Difficulty,
+    /// Index of the campaign picked in the menu
+    pub campaign: usize,
+    pub heal: Heal,
+    /// Speed of the game, lower gives more time to react
+    pub time_scale: f32,
+    /// Mark where searching guards think the player is
+    pub show_last_seen: bool,
+    /// Draw guards that didn't notice anything half transparent
+    pub fade_idle_enemies: bool,
+    /// Part of the screen height kept free around the HUD
+    pub hud_inset: f32,
+    /// Starting camera zoom in levels, above 1 follows the player
+    pub zoom: f32,
+    /// Seconds the death screen takes to fade in, 0 shows it at once
+    pub death_fade: f32,
+    /// Replay the path of the previous life after restarting
+    pub show_ghost: bool,
+    /// Dead guards fade away after a while
+    pub fade_corpses: bool,
+    /// Show recent phrases as captions from the start of a level
+    pub captions: bool,
+    /// Draw what the guards see, on by default in cheat builds
+    pub show_vision_cones: bool,
+    /// Also save on swapping items with a crate, not only on entering a room
+    pub save_on_swap: bool,
+    /// Pick up dropped items by walking over them, crates are still opened with E
+    pub pickup_on_walk: bool,
+    /// Seconds a fully printed scene card stays before the next one, `None` waits for a key
+    pub auto_advance: Option<f32>,
+    /// Scales both music and sound effects
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+    /// Angle in degrees that guards see in front of them
+    pub enemy_fov: f32,
+    /// How far guards see
+    pub enemy_view_range: f32,
+    /// Random seed of every level, overrides the seeds of the levels
+    pub seed: Option<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            keys: KeyBindings::default(),
+            difficulty: Difficulty::default(),
+            campaign: 0,
+            heal: Heal::default(),
+            time_scale: 1.,
+            show_last_seen: true,
+            fade_idle_enemies: false,
+            hud_inset: 0.,
+            zoom: 1.,
+            death_fade: 1.,
+            show_ghost: true,
+            fade_corpses: false,
+            captions: false,
+            show_vision_cones: cfg!(feature = "cheat"),
+            save_on_swap: false,
+            pickup_on_walk: false,
+            auto_advance: None,
+            master_volume: 1.,
+            music_volume: 0.75,
+            sfx_volume: 1.,
+            enemy_fov: ENEMY_FOV,
+            enemy_view_range: RATIO_W_H,
+            seed: None,
+        }
+    }
+}
+
+/// Settings that can be changed in settings.yaml, missing ones keep their defaults
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SettingsConfig {
+    difficulty: Option<Difficulty>,
+    time_scale: Option<f32>,
+    heal: HealConfig,
+    show_last_seen: Option<bool>,
+    fade_idle_enemies: Option<bool>,
+    hud_inset: Option<f32>,
+    show_ghost: Option<bool>,
+    fade_corpses: Option<bool>,
+    save_on_swap: Option<bool>,
+    pickup_on_walk: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct HealConfig {
+    enabled: Option<bool>,
+    time: Option<f32>,
+    out_of_combat: Option<bool>,
+}
+
+impl Settings {
+    /// Settings from the text of settings.yaml, broken settings give the defaults
+    pub fn load(config: Option<&str>) -> Self {
+        let settings = Self::default();
+        let Some(config) = config else {
+            return settings;
+        };
+        let config: SettingsConfig = match serde_yaml::from_str(config) {
+            Ok(config) => config,
+            Err(err) => {
+                warn!("Settings are broken, using defaults: {err}");
+                return settings;
+            }
+        };
+        Self {
+            difficulty: config.difficulty.unwrap_or(settings.difficulty),
+            time_scale: config.time_scale.unwrap_or(settings.time_scale),
+            heal: Heal {
+                enabled: config.heal.enabled.unwrap_or(settings.heal.enabled),
+                time: config.heal.time.unwrap_or(settings.heal.time),
+                out_of_combat: config
+                    .heal
+                    .out_of_combat
+                    .unwrap_or(settings.heal.out_of_combat),
+            },
+            show_last_seen: config.show_last_seen.unwrap_or(settings.show_last_seen),
+            fade_idle_enemies: config
+                .fade_idle_enemies
+                .unwrap_or(settings.fade_idle_enemies),
+            hud_inset: config.hud_inset.unwrap_or(settings.hud_inset),
+            show_ghost: config.show_ghost.unwrap_or(settings.show_ghost),
+            fade_corpses: config.fade_corpses.unwrap_or(settings.fade_corpses),
+            save_on_swap: config.save_on_swap.unwrap_or(settings.save_on_swap),
+            pickup_on_walk: config.pickup_on_walk.unwrap_or(settings.pickup_on_walk),
+            ..settings
+        }
+    }
+
+    /// Next game speed of the settings menu, from the fastest it goes back to the slowest
+    pub fn next_time_scale(&self) -> f32 {
+        if self.time_scale() >= MAX_TIME_SCALE {
+            MIN_TIME_SCALE
+        } else {
+            (self.time_scale() + TIME_SCALE_STEP).min(MAX_TIME_SCALE)
+        }
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        clamp(self.time_scale, MIN_TIME_SCALE, MAX_TIME_SCALE)
+    }
+
+    pub fn music_volume(&self) -> f32 {
+        clamp(self.master_volume, 0., 1.) * clamp(self.music_volume, 0., 1.)
+    }
+
+    pub fn sfx_volume(&self) -> f32 {
+        clamp(self.master_volume, 0., 1.) * clamp(self.sfx_volume, 0., 1.)
+    }
+
+    pub fn change_volume(&mut self, step: f32) {
+        self.master_volume = clamp(self.master_volume + step, 0., 1.);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_keys_move_like_the_defaults() {
+        let keys = KeyBindings::load(Some("{up: [E], down: [D], left: [S], right: [F]}"));
+        let moved = |held: &[KeyCode]| keys.move_direction(|key| held.contains(&key));
+        assert_eq!(moved(&[KeyCode::E]), (0, -1));
+        assert_eq!(moved(&[KeyCode::D]), (0, 1));
+        assert_eq!(moved(&[KeyCode::S]), (-1, 0));
+        assert_eq!(moved(&[KeyCode::F]), (1, 0));
+        assert_eq!(moved(&[KeyCode::E, KeyCode::F]), (1, -1));
+        // Opposite keys cancel each other out
+        assert_eq!(moved(&[KeyCode::S, KeyCode::F]), (0, 0));
+        assert_eq!(moved(&[KeyCode::E, KeyCode::D, KeyCode::F]), (1, 0));
+        // The old keys don't move any more
+        assert_eq!(moved(&[KeyCode::W, KeyCode::A]), (0, 0));
+        let defaults = KeyBindings::default();
+        assert_eq!(
+            defaults.move_direction(|key| key == KeyCode::W || key == KeyCode::D),
+            moved(&[KeyCode::E, KeyCode::F])
+        );
+    }
+
+    #[test]
+    fn settings_file_overrides_the_defaults() {
+        let settings = Settings::load(Some(
+            "
+difficulty: Hard
+time_scale: 0.5
+heal:
+  out_of_combat: true
+show_last_seen: false
+fade_idle_enemies: true
+hud_inset: 0.05
+show_ghost: false
+fade_corpses: true
+save_on_swap: true
+",
+        ));
+        assert_eq!(settings.difficulty, Difficulty::Hard);
+        assert_eq!(settings.time_scale(), 0.5);
+        assert!(settings.heal.out_of_combat);
+        assert!(settings.heal.enabled);
+        assert!(!settings.show_last_seen);
+        assert!(settings.fade_idle_enemies);
+        assert_eq!(settings.hud_inset, 0.05);
+        assert!(!settings.show_ghost);
+        assert!(settings.fade_corpses);
+        assert!(settings.save_on_swap);
+        assert!(!settings.pickup_on_walk);
+    }
+
+    #[test]
+    fn broken_settings_file_gives_the_defaults() {
+        let defaults = Settings::default();
+        for config in [None, Some("speed: 2"), Some("time_scale: fast")] {
+            let settings = Settings::load(config);
+            assert_eq!(settings.time_scale, defaults.time_scale);
+            assert_eq!(settings.difficulty, defaults.difficulty);
+        }
+    }
+
+    #[test]
+    fn game_speed_goes_around() {
+        let mut settings = Settings::default();
+        let mut speeds = Vec::new();
+        for _ in 0..5 {
+            settings.time_scale = settings.next_time_scale();
+            speeds.push(settings.time_scale);
+        }
+        assert_eq!(speeds, [1.25, 1.5, 0.5, 0.75, 1.]);
+    }
+}